@@ -1,28 +1,316 @@
-use std::process;
+use std::process::{self, Command, Stdio};
 use std::env;
-use std::path::Path;
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
 extern crate xdg;
-use udman;
+use udman::{Client, DeviceStatus};
+
+const USAGE: &str = "Usage: udman [--config <path>] [--fallback-to-defaults] [command]
+
+Commands:
+  daemon                   Watch for devices and handle them, the default
+  list [--json]            List devices with their drives, state and mount points
+  info <device> [--json]   Show everything known about a device
+  mount <device>           Mount a filesystem
+  unmount <device>         Unmount a filesystem
+  unlock <device> [--ask [--remember]]
+                           Unlock an encrypted device with its configured key,
+                           or with a passphrase asked for on the terminal,
+                           --remember saves it in the keyring
+  lock <device>            Unmount and lock an encrypted device
+  eject <drive> [--lazy]   Unmount and lock everything on a drive and power it off
+  watch [--uuid <pattern>] [--label <pattern>] [--event <name>[,<name>]] [--once]
+                           Print events as lines of JSON as they happen,
+                           --once exits after the first
+  always-mount <uuid>      Always mount a device when it's attached
+  never-mount <uuid>       Never mount a device when it's attached
+
+Devices can be given as a device file, UUID, label or UDisks2 object path.
+Commands are carried out by the running udman if there is one, otherwise
+UDisks2 is used directly.";
+
+/// Commands, the number of arguments each takes and the flags they accept
+const COMMANDS: &[(&str, usize, &[&str])] = &[
+    ("daemon", 0, &[]),
+    ("list", 0, &["--json"]),
+    ("info", 1, &["--json"]),
+    ("mount", 1, &[]),
+    ("unmount", 1, &[]),
+    ("unlock", 1, &["--ask", "--remember"]),
+    ("lock", 1, &[]),
+    ("eject", 1, &["--lazy"]),
+    ("watch", 0, &["--uuid", "--label", "--event", "--once"]),
+    ("always-mount", 1, &[]),
+    ("never-mount", 1, &[])
+];
 
 fn main() {
     let xdg_dirs = xdg::BaseDirectories::with_prefix("udman").unwrap();
-    let mut args = env::args();
+    let mut config_arg = None;
+    let mut fallback_to_defaults = false;
+    let mut flags = Vec::new();
+    let mut values = Vec::new();
+    let mut operands = Vec::new();
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fallback-to-defaults" => fallback_to_defaults = true,
+            "--config" => match args.next() {
+                Some(path) => config_arg = Some(PathBuf::from(path)),
+                None => usage_error("--config needs a path")
+            },
+            "--uuid" | "--label" | "--event" => match args.next() {
+                Some(value) => {
+                    values.push((arg.to_owned(), value));
+                    flags.push(arg);
+                },
+                None => usage_error(&format!("{} needs a value", arg))
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ if arg.starts_with("--") => flags.push(arg),
+            _ => operands.push(arg)
+        }
+    }
+
+    // Before there were commands the only argument was the config file, a
+    // bare path still starts the daemon with it
+    let command = match operands.first() {
+        Some(first) if COMMANDS.iter().any(|(command, _, _)| command == first) => operands.remove(0),
+        Some(_) if operands.len() == 1 && config_arg.is_none() => {
+            config_arg = Some(PathBuf::from(operands.remove(0)));
+            "daemon".to_owned()
+        },
+        Some(first) => usage_error(&format!("Unknown command {}", first)),
+        None => "daemon".to_owned()
+    };
 
-    args.next();
+    let (_, arity, allowed) = COMMANDS.iter().find(|(name, _, _)| *name == command).unwrap();
 
-    let config = match args.next() {
-        Some(config_file) => udman::Config::parse(Path::new(&config_file)),
-        None => match xdg_dirs.find_config_file("config.toml") {
-            Some(config_file) => udman::Config::parse(&config_file),
-            None => udman::Config::new()
+    if operands.len() != *arity {
+        usage_error(&format!("Wrong number of arguments for {}", command));
+    }
+
+    if let Some(flag) = flags.iter().find(|flag| !allowed.contains(&flag.as_str())) {
+        usage_error(&format!("Unknown option {} for {}", flag, command));
+    }
+
+    let has_flag = |flag: &str| flags.iter().any(|candidate| candidate == flag);
+    let value = |flag: &str| values.iter().rev().find(|(name, _)| name == flag).map(|(_, value)| value.to_owned());
+
+    // If there's no config file yet then keep track of where it would be
+    // so that it's picked up once it's created
+    let config_file = config_arg.or_else(|| xdg_dirs.find_config_file("config.toml"));
+    let config = config_file.as_ref().map(|config_file| udman::Config::load(config_file));
+    let config_file = config_file.unwrap_or_else(|| xdg_dirs.get_config_home().join("config.toml"));
+
+    // A running udman picks up the change as soon as the file is written
+    if command == "always-mount" || command == "never-mount" {
+        if let Err(e) = udman::Config::remember_automount(&config_file, &operands[0], command == "always-mount") {
+            eprintln!("{}", e);
+            process::exit(1);
         }
+
+        return;
+    }
+
+    let config = match config {
+        Some(Ok(config)) => config,
+        Some(Err(e)) => {
+            eprintln!("Invalid configuration\n  {}", e);
+
+            if !fallback_to_defaults {
+                eprintln!("Fix the configuration or pass --fallback-to-defaults to continue with the default settings");
+                process::exit(2);
+            }
+
+            eprintln!("Continuing with the default settings");
+            udman::Config::new()
+        },
+        None => udman::Config::new()
     };
 
-    println!("{:?}", config);
+    if command == "daemon" {
+        if let Err(e) = udman::run(config, &config_file) {
+            eprintln!("Application error: {}", e);
+            process::exit(1);
+        }
+
+        return;
+    }
+
+    // Events are seen straight from UDisks2, the daemon isn't needed
+    if command == "watch" {
+        let filter = udman::WatchFilter {
+            uuid: value("--uuid"),
+            label: value("--label"),
+            events: values.iter()
+                .filter(|(name, _)| name == "--event")
+                .flat_map(|(_, events)| events.split(','))
+                .map(|event| event.trim().to_owned())
+                .filter(|event| !event.is_empty())
+                .collect(),
+            once: has_flag("--once")
+        };
+
+        if let Err(e) = udman::watch(filter, &config.events) {
+            fail(e);
+        }
+
+        return;
+    }
+
+    let client = Client::new(config).unwrap_or_else(|e| fail(e));
+    let name = operands.first().map(String::as_str).unwrap_or_default();
+
+    match command.as_str() {
+        "list" => {
+            let devices = client.list().unwrap_or_else(|e| fail(e));
+
+            if has_flag("--json") {
+                println!("{}", serde_json::to_string_pretty(&devices).unwrap());
+            } else {
+                print_table(&devices);
+            }
+        },
+        "info" => {
+            let device = client.info(name).unwrap_or_else(|e| fail(e));
+
+            if has_flag("--json") {
+                println!("{}", serde_json::to_string_pretty(&device).unwrap());
+            } else {
+                print_info(&device);
+            }
+        },
+        "mount" => println!("{}", client.mount(name).unwrap_or_else(|e| fail(e))),
+        "unmount" => client.unmount(name).unwrap_or_else(|e| fail(e)),
+        "unlock" => {
+            if has_flag("--remember") && !has_flag("--ask") {
+                usage_error("--remember needs --ask");
+            }
+
+            let passphrase = if has_flag("--ask") { Some(ask_passphrase(name)) } else { None };
+            let cleartext_device = client.unlock(name, passphrase, has_flag("--remember")).unwrap_or_else(|e| fail(e));
 
-    if let Err(e) = udman::run(config) {
-        eprintln!("Application error: {}", e);
-        process::exit(1);
+            // Show the device file rather than the object path when it's known
+            match client.info(&cleartext_device) {
+                Ok(device) => println!("{}", device.device),
+                Err(_) => println!("{}", cleartext_device)
+            }
+        },
+        "lock" => client.lock(name).unwrap_or_else(|e| fail(e)),
+        "eject" => client.eject(name, has_flag("--lazy")).unwrap_or_else(|e| fail(e)),
+        _ => unreachable!()
     }
 }
 
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn fail(e: impl std::fmt::Display) -> ! {
+    eprintln!("{}", e);
+    process::exit(1);
+}
+
+/// Prints devices with the unlocked device of each encrypted one below it
+fn print_table(devices: &[DeviceStatus]) {
+    let mut rows = vec![["DEVICE", "SIZE", "TYPE", "LABEL", "DRIVE", "STATE"].iter().map(|heading| heading.to_string()).collect::<Vec<_>>()];
+
+    // Devices without media such as unused loop devices aren't worth listing
+    let listed = devices.iter().filter(|device| device.size > 0);
+
+    for device in listed.filter(|device| device.backing_device.is_none()) {
+        rows.push(row(device, ""));
+
+        let cleartext = devices.iter().find(|cleartext| device.cleartext_device.as_ref() == Some(&cleartext.object_path));
+
+        if let Some(cleartext) = cleartext {
+            rows.push(row(cleartext, "└─"));
+        }
+    }
+
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|column| rows.iter().map(|row| row[column].chars().count()).max().unwrap_or(0))
+        .collect();
+
+    for row in rows {
+        let cells: Vec<String> = row.iter().zip(widths.iter()).map(|(cell, width)| format!("{:width$}", cell, width = width)).collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+}
+
+fn row(device: &DeviceStatus, prefix: &str) -> Vec<String> {
+    vec![
+        format!("{}{}", prefix, device.device),
+        udman::format_size(device.size),
+        device.fstype.to_owned().unwrap_or_default(),
+        device.label.to_owned().unwrap_or_default(),
+        device.drive_name.to_owned().unwrap_or_default(),
+        state(device)
+    ]
+}
+
+/// Where a device is mounted, or whether it's unlocked if it's encrypted
+fn state(device: &DeviceStatus) -> String {
+    match (&device.mount_path, device.encrypted, &device.cleartext_device) {
+        (Some(mount_path), _, _) => mount_path.to_owned(),
+        (None, true, Some(_)) => "unlocked".to_owned(),
+        (None, true, None) => "locked".to_owned(),
+        (None, false, _) if device.filesystem => "not mounted".to_owned(),
+        _ => String::new()
+    }
+}
+
+fn print_info(device: &DeviceStatus) {
+    let fields = [
+        ("Device", Some(device.device.to_owned())),
+        ("Object path", Some(device.object_path.to_owned())),
+        ("UUID", device.uuid.to_owned()),
+        ("Label", device.label.to_owned()),
+        ("Type", device.fstype.to_owned()),
+        ("Size", Some(udman::format_size(device.size))),
+        ("State", Some(state(device)).filter(|state| !state.is_empty())),
+        ("Unlocked as", device.cleartext_device.to_owned()),
+        ("Unlocked from", device.backing_device.to_owned()),
+        ("Drive", device.drive_name.to_owned().or_else(|| device.drive.to_owned())),
+        ("Removable", device.removable.map(|removable| if removable { "yes" } else { "no" }.to_owned()))
+    ];
+
+    for (name, value) in fields.iter() {
+        if let Some(value) = value {
+            println!("{:14}{}", format!("{}:", name), value);
+        }
+    }
+}
+
+/// Reads a passphrase from the terminal without echoing it
+fn ask_passphrase(device: &str) -> String {
+    eprint!("Passphrase for {}: ", device);
+
+    let stty = |arg: &str| {
+        File::open("/dev/tty")
+            .and_then(|tty| Command::new("stty").arg(arg).stdin(tty).stderr(Stdio::null()).status())
+            .map(|status| status.success())
+            .unwrap_or(false)
+    };
+    let hidden = stty("-echo");
+    let mut passphrase = String::new();
+    let read = io::stdin().lock().read_line(&mut passphrase);
+
+    if hidden {
+        stty("echo");
+        eprintln!();
+    }
+
+    if let Err(e) = read {
+        fail(e);
+    }
+
+    passphrase.trim_end_matches(&['\r', '\n'][..]).to_owned()
+}
@@ -0,0 +1,232 @@
+use dbus::arg::{RefArg, Variant};
+use dbus::blocking::{Connection, Proxy};
+use dbus::strings::Path;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use crate::config::Config;
+use crate::err::MounterError;
+use crate::jobs::{self, Action, Outcome};
+use crate::keyring::Keyring;
+use crate::manager::{self, UnlockWith};
+use crate::service::{self, DeviceStatus};
+use crate::udisks2::{self, devices::{Block, Drive}};
+
+/// How long to wait for the daemon to answer, mounting and unlocking can
+/// wait on an authentication dialog
+const DAEMON_TIMEOUT: Duration = Duration::from_secs(300);
+
+type DeviceProperties = HashMap<String, Variant<Box<dyn RefArg>>>;
+
+/// Carries out commands from the command line. They're handed to a running
+/// udman over D-Bus so its notifications, hooks and events follow, if
+/// udman isn't running UDisks2 is asked directly
+pub enum Client {
+    /// Commands go to the udman that owns `org.udman.Manager1`
+    Daemon(Connection),
+    /// Commands go straight to UDisks2
+    Direct(Box<Direct>)
+}
+
+/// The devices UDisks2 knows about, for when udman isn't running
+pub struct Direct {
+    config: Config,
+    drives: HashMap<String, Drive>,
+    devices: HashMap<String, Block>
+}
+
+impl Client {
+    /// Connects to a running udman, or reads the devices from UDisks2 if
+    /// there isn't one. `config` gives mount options and keys for devices
+    /// when UDisks2 is used directly
+    pub fn new(config: Config) -> Result<Self, MounterError> {
+        if let Some(conn) = daemon() {
+            return Ok(Client::Daemon(conn));
+        }
+
+        let state = udisks2::current_state().map_err(MounterError::DBusError)?;
+        let mut drives = HashMap::new();
+        let mut devices = HashMap::new();
+
+        for (object_path, interfaces_and_properties) in state.iter() {
+            if let Some(drive) = Drive::new(object_path, interfaces_and_properties) {
+                drives.insert(object_path.to_string(), drive);
+            }
+
+            if let Some(device) = Block::new(object_path, interfaces_and_properties) {
+                devices.insert(object_path.to_string(), device);
+            }
+        }
+
+        Ok(Client::Direct(Box::new(Direct { config, drives, devices })))
+    }
+
+    /// Every device, in order of their device files
+    pub fn list(&self) -> Result<Vec<DeviceStatus>, MounterError> {
+        match self {
+            Client::Daemon(conn) => {
+                let (devices,): (Vec<DeviceProperties>,) = proxy(conn)
+                    .method_call(service::INTERFACE, "ListDevices", ())
+                    .map_err(MounterError::DBusError)?;
+
+                Ok(devices.iter().filter_map(DeviceStatus::from_properties).collect())
+            },
+            Client::Direct(direct) => {
+                let devices = manager::devices(&direct.devices, &direct.drives);
+
+                Ok(devices.into_iter().map(|(device, drive)| DeviceStatus::new(device, drive)).collect())
+            }
+        }
+    }
+
+    /// A device found by its object path, device file, UUID or label
+    pub fn info(&self, name: &str) -> Result<DeviceStatus, MounterError> {
+        self.list()?
+            .into_iter()
+            .find(|status| {
+                status.object_path == name
+                    || status.device == name
+                    || status.uuid.as_deref() == Some(name)
+                    || status.label.as_deref() == Some(name)
+            })
+            .ok_or_else(|| MounterError::UnknownDevice(name.to_owned()))
+    }
+
+    /// Mounts a filesystem, returning where it's mounted
+    pub fn mount(&self, name: &str) -> Result<String, MounterError> {
+        match self {
+            Client::Daemon(conn) => {
+                let (mount_path,): (String,) = proxy(conn)
+                    .method_call(service::INTERFACE, "Mount", (name,))
+                    .map_err(MounterError::DBusError)?;
+
+                Ok(mount_path)
+            },
+            Client::Direct(direct) => direct.mount(name)
+        }
+    }
+
+    /// Unmounts a filesystem, for encrypted devices the one inside
+    pub fn unmount(&self, name: &str) -> Result<(), MounterError> {
+        match self {
+            Client::Daemon(conn) => proxy(conn).method_call(service::INTERFACE, "Unmount", (name,)).map_err(MounterError::DBusError),
+            Client::Direct(direct) => direct.unmount(name)
+        }
+    }
+
+    /// Unlocks an encrypted device with a passphrase, or with its configured
+    /// keyfile or password or the passphrase saved in the keyring if none is
+    /// given. `remember` saves a passphrase that's given in the keyring once
+    /// it's worked. Returns the object path of the cleartext device
+    pub fn unlock(&self, name: &str, passphrase: Option<String>, remember: bool) -> Result<String, MounterError> {
+        match self {
+            Client::Daemon(conn) => {
+                let (cleartext_device,): (Path<'static>,) = proxy(conn)
+                    .method_call(service::INTERFACE, "Unlock", (name, passphrase.unwrap_or_default(), remember))
+                    .map_err(MounterError::DBusError)?;
+
+                Ok(cleartext_device.to_string())
+            },
+            Client::Direct(direct) => direct.unlock(name, passphrase, remember)
+        }
+    }
+
+    /// Locks an encrypted device, unmounting the filesystem inside first
+    pub fn lock(&self, name: &str) -> Result<(), MounterError> {
+        match self {
+            Client::Daemon(conn) => proxy(conn).method_call(service::INTERFACE, "Lock", (name,)).map_err(MounterError::DBusError),
+            Client::Direct(direct) => direct.lock(name)
+        }
+    }
+
+    /// Unmounts and locks everything on a drive and then powers it off
+    pub fn eject(&self, name: &str, lazy: bool) -> Result<(), MounterError> {
+        match self {
+            Client::Daemon(conn) => proxy(conn).method_call(service::INTERFACE, "Eject", (name, lazy)).map_err(MounterError::DBusError),
+            Client::Direct(direct) => direct.eject(name, lazy)
+        }
+    }
+}
+
+impl Direct {
+    fn find_device(&self, name: &str) -> Result<&Block, MounterError> {
+        manager::find_device(&self.devices, name).ok_or_else(|| MounterError::UnknownDevice(name.to_owned()))
+    }
+
+    fn mount(&self, name: &str) -> Result<String, MounterError> {
+        let device = manager::find_filesystem(&self.devices, name)?;
+        let backing_device = manager::backing_device(&self.devices, device);
+        let settings = self.config.settings_for(device, backing_device, manager::drive_for(&self.devices, &self.drives, device));
+
+        device.as_fs()
+            .ok_or_else(|| MounterError::NotFilesystem(name.to_owned()))?
+            .mount(settings.options.as_deref(), settings.fstype.as_deref())
+            .map_err(|e| MounterError::MountFailed(device.device.to_owned(), e.message().unwrap_or("D-Bus error").to_owned()))
+    }
+
+    fn unmount(&self, name: &str) -> Result<(), MounterError> {
+        let device = manager::find_filesystem(&self.devices, name)?;
+
+        match (manager::mount_path(device), device.as_fs()) {
+            (Some(mount_path), Some(filesystem)) => filesystem.unmount(false).map_err(|e| manager::unmount_error(mount_path, e)),
+            _ => Ok(())
+        }
+    }
+
+    fn unlock(&self, name: &str, passphrase: Option<String>, remember: bool) -> Result<String, MounterError> {
+        let action = match manager::unlock_with(&self.config, &self.devices, &self.drives, name, passphrase, remember)? {
+            UnlockWith::Unlocked(cleartext_device) => return Ok(cleartext_device),
+            UnlockWith::Keys { encrypted, keys, remember } => Action::unlock(encrypted, keys, Keyring::new(&self.config.keyring), remember),
+            UnlockWith::KeyDevice { encrypted, key_device, path } => Action::UnlockWithKeyDevice { encrypted, key_device: Box::new(key_device), path }
+        };
+
+        match jobs::run(action, &AtomicBool::new(false)) {
+            Outcome::Unlocked(result) => result,
+            _ => unreachable!()
+        }
+    }
+
+    fn lock(&self, name: &str) -> Result<(), MounterError> {
+        let device = self.find_device(name)?;
+        let encrypted = device.as_enc().ok_or_else(|| MounterError::NotEncrypted(name.to_owned()))?;
+
+        let cleartext_device = match device.enc_info.as_ref().and_then(|enc_info| enc_info.cleartext_device.as_ref()) {
+            Some(cleartext_device) => cleartext_device,
+            None => return Ok(())
+        };
+
+        if self.devices.contains_key(cleartext_device) {
+            self.unmount(cleartext_device)?;
+        }
+
+        encrypted.lock().map_err(|e| MounterError::LockFailed(device.device.to_owned(), e))
+    }
+
+    fn eject(&self, name: &str, lazy: bool) -> Result<(), MounterError> {
+        let drive = manager::find_drive(&self.devices, &self.drives, name)
+            .ok_or_else(|| MounterError::UnknownDrive(name.to_owned()))?;
+
+        let (_, steps) = manager::eject_steps(&self.devices, drive);
+
+        for step in steps {
+            step.result(drive, jobs::run(step.action(drive, lazy), &AtomicBool::new(false)))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A connection to the running udman, if there is one
+fn daemon() -> Option<Connection> {
+    let conn = Connection::new_session().ok()?;
+    let (has_owner,): (bool,) = conn
+        .with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", Duration::from_millis(5000))
+        .method_call("org.freedesktop.DBus", "NameHasOwner", (service::BUS_NAME,))
+        .ok()?;
+
+    Some(conn).filter(|_| has_owner)
+}
+
+fn proxy(conn: &Connection) -> Proxy<'_, &Connection> {
+    conn.with_proxy(service::BUS_NAME, service::OBJECT_PATH, DAEMON_TIMEOUT)
+}
@@ -1,26 +1,44 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
 use serde::Deserialize;
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use crate::err::ConfigError;
+use crate::udisks2::devices::{Block, Drive};
+use crate::hooks::Hook;
+use crate::notices;
+use crate::notifications::Urgency;
+use glob::Pattern;
 
 /// Parses the config file and sets helpful defaults
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     /// Base settings when dealing with filesystems
     #[serde(default)]
     pub settings: Settings,
     /// Mappings between UUIDs and their settings
-    pub uuid: Option<HashMap<String, FsSettings>>
+    pub uuid: Option<HashMap<String, FsSettings>>,
+    /// Rules matching devices by their properties, checked in order
+    #[serde(default)]
+    pub rule: Vec<Rule>,
+    /// Default settings for each filesystem type
+    pub fstype: Option<HashMap<String, FstypeSettings>>,
+    /// How notifications look and which ones are shown
+    #[serde(default)]
+    pub notifications: NotificationSettings,
+    /// Where device events are published
+    #[serde(default)]
+    pub events: EventSettings,
+    /// Where passphrases of encrypted devices are looked up and saved
+    #[serde(default)]
+    pub keyring: KeyringSettings
 }
 
 impl Config {
     /// Create default config
     pub fn new() -> Self {
-        Config {
-            settings: Settings::default(),
-            uuid: None
-        }
+        Config::default()
     }
 
     /// Fetches specific configuration for a UUID if any exists
@@ -28,45 +46,237 @@ impl Config {
         self.uuid.as_ref()?.get(uuid)
     }
 
-    /// Parse configuration file and return an instance of Config
-    /// with the settings specified
-    pub fn parse(path: &Path) -> Self {
-        // If the file can be read then parse it otherwise
-        // return an empty config file
-        if let Ok(mut file) = File::open(path) {
-            let mut contents = String::new();
+    /// Whether the filesystem with `uuid` holds keyfiles for encrypted
+    /// devices, in which case udman only mounts it to read them
+    pub(crate) fn holds_keyfiles(&self, uuid: &str) -> bool {
+        self.uuid.iter().flat_map(HashMap::values)
+            .chain(self.rule.iter().map(|rule| &rule.settings))
+            .any(|settings| matches!(&settings.keyfile, Some(Keyfile::OnDevice(key)) if key.uuid == uuid))
+    }
 
-            // Read config file into string and convert into Config struct
-            file.read_to_string(&mut contents).expect("Could not read file");
-            toml::from_str(contents.as_str()).unwrap()
-        } else {
-            eprintln!("Could not read config file: {:?}", path);
-            Config::new()
+    /// Works out the settings for a device. Settings for the device's UUID
+    /// take precedence over the first matching rule, anything left unset
+    /// falls back to `[settings]`
+    pub(crate) fn resolve(&self, device: &Block, drive: Option<&Drive>) -> FsSettings {
+        let uuid_settings = device.uuid.as_ref().and_then(|uuid| self.get_uuid_settings(uuid));
+        let rule_settings = self.rule.iter()
+            .find(|rule| rule.matches.is_match(device, drive))
+            .map(|rule| &rule.settings);
+
+        let mut settings = match (uuid_settings, rule_settings) {
+            (Some(uuid_settings), Some(rule_settings)) => uuid_settings.merge(rule_settings),
+            (Some(settings), None) | (None, Some(settings)) => settings.clone(),
+            (None, None) => FsSettings::default()
+        };
+
+        // Fall back to the default options for the type being mounted
+        if settings.options.is_none() {
+            let fstype = settings.fstype.as_ref().or(device.id_type.as_ref());
+
+            settings.options = fstype
+                .and_then(|fstype| self.fstype.as_ref()?.get(fstype))
+                .and_then(|fstype_settings| fstype_settings.options.clone());
+        }
+
+        settings
+    }
+
+    /// Settings for a device on `drive`. Cleartext devices also pick up the
+    /// settings of the encrypted device backing them, their own UUID section
+    /// still takes precedence
+    pub(crate) fn settings_for(&self, device: &Block, backing_device: Option<&Block>, drive: Option<&Drive>) -> FsSettings {
+        let settings = self.resolve(device, drive);
+
+        match backing_device {
+            Some(backing_device) => {
+                let backing_settings = self.resolve(backing_device, drive);
+
+                match device.uuid.as_ref().and_then(|uuid| self.get_uuid_settings(uuid)) {
+                    Some(uuid_settings) => uuid_settings.merge(&backing_settings).merge(&settings),
+                    None => backing_settings.merge(&settings)
+                }
+            },
+            None => settings
+        }
+    }
+
+    /// Read and parse the configuration file. Misspelled options and values
+    /// that can't be used are reported as errors rather than being ignored
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let mut contents = String::new();
+
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+
+        let mut config: Config = toml::from_str(contents.as_str())
+            .map_err(|e| ConfigError::from_toml(path, e))?;
+
+        if let Some(dir) = std::env::current_dir().ok().map(|cwd| cwd.join(path)).as_deref().and_then(Path::parent) {
+            config.resolve_run(dir);
         }
+
+        config.validate().map_err(|(key, reason)| ConfigError::InvalidValue {
+            path: path.to_owned(),
+            key,
+            reason
+        })?;
+
+        Ok(config)
+    }
+
+    /// Saves whether a device should always or never be mounted by setting
+    /// `automount` in its `[uuid.<uuid>]` section. The rest of the file,
+    /// including comments and formatting, is left as it is. The file is
+    /// created if it doesn't exist yet
+    pub fn remember_automount(path: &Path, uuid: &str, automount: bool) -> Result<(), ConfigError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(ConfigError::Io(path.to_owned(), e))
+        };
+
+        let mut document: toml_edit::Document = contents.parse()
+            .map_err(|e| ConfigError::from_toml_edit(path, &contents, e))?;
+
+        // Don't write an empty `[uuid]` header above the device's section
+        if !document.contains_key("uuid") {
+            let mut uuids = toml_edit::Table::new();
+            uuids.set_implicit(true);
+            document.insert("uuid", toml_edit::Item::Table(uuids));
+        }
+
+        let settings = &mut document["uuid"][uuid];
+
+        if settings.is_none() {
+            *settings = toml_edit::table();
+        }
+
+        match settings.get_mut("automount").and_then(toml_edit::Item::as_value_mut) {
+            // Keep any comment next to the existing value
+            Some(value) => {
+                let decor = value.decor().clone();
+                *value = toml_edit::Value::from(automount);
+                *value.decor_mut() = decor;
+            },
+            None => settings["automount"] = toml_edit::value(automount)
+        }
+
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory).map_err(|e| ConfigError::Write(path.to_owned(), e))?;
+        }
+
+        fs::write(path, document.to_string()).map_err(|e| ConfigError::Write(path.to_owned(), e))
+    }
+
+    /// Makes relative `run` paths, which were accepted before hooks had to be
+    /// absolute paths, relative to `dir`, the directory of the config file
+    fn resolve_run(&mut self, dir: &Path) {
+        let settings = self.uuid.iter_mut().flat_map(HashMap::values_mut)
+            .chain(self.rule.iter_mut().map(|rule| &mut rule.settings));
+
+        for settings in settings {
+            if let Some(run) = &mut settings.run {
+                *run = dir.join(&run).to_string_lossy().into_owned();
+            }
+        }
+    }
+
+    /// Checks values that deserialize fine but can't be used, returning the
+    /// offending key and the reason
+    fn validate(&self) -> Result<(), (String, String)> {
+        self.settings.validate().map_err(|(key, reason)| (format!("settings.{}", key), reason))?;
+        self.notifications.validate().map_err(|(key, reason)| (format!("notifications.{}", key), reason))?;
+        self.events.validate().map_err(|(key, reason)| (format!("events.{}", key), reason))?;
+        self.keyring.validate().map_err(|(key, reason)| (format!("keyring.{}", key), reason))?;
+
+        if let Some(uuids) = &self.uuid {
+            for (uuid, settings) in uuids {
+                settings.validate().map_err(|(key, reason)| (format!("uuid.{}.{}", uuid, key), reason))?;
+            }
+        }
+
+        for (index, rule) in self.rule.iter().enumerate() {
+            rule.matches.validate().map_err(|(key, reason)| (format!("rule[{}].match.{}", index, key), reason))?;
+            rule.settings.validate().map_err(|(key, reason)| (format!("rule[{}].settings.{}", index, key), reason))?;
+        }
+
+        Ok(())
     }
 }
 
 /// Generic settings when dealing with devices, can be overwritten on a per
 /// filesystem basis
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 // Sets all fields to default() values and
 // overwrites ones that are present in toml file
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct Settings {
     /// Should filesystems be mounted automatically
     pub automount: bool,
+    /// Scripts to run for every device
+    pub hooks: Hooks,
+    /// How the user is asked for passphrases, a dialog box if not given
+    pub prompt: Option<PromptSettings>,
+    /// How many times the user is asked for a passphrase before giving up,
+    /// defaults to 3
+    pub unlock_attempts: Option<u32>
 }
 
-impl Default for Settings {
-    fn default() -> Self {
-        Self {
-            automount: false,
+impl Settings {
+    fn validate(&self) -> Result<(), (String, String)> {
+        self.hooks.validate()?;
+
+        if let Some(prompt) = &self.prompt {
+            prompt.validate()?;
+        }
+
+        if self.unlock_attempts == Some(0) {
+            return Err(("unlock_attempts".to_owned(), "must be at least 1".to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
+/// A way of asking the user for a passphrase
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum PromptSettings {
+    /// A dialog box shown by zenity, kdialog or whichever is installed
+    Dialog,
+    /// A pinentry program, spoken to with the Assuan protocol
+    Pinentry {
+        /// Absolute path of the program, `pinentry` is used if not given
+        program: Option<String>
+    },
+    /// `systemd-ask-password`, answered by whichever password agent is
+    /// running
+    Systemd,
+    /// A program in the style of `SSH_ASKPASS` that's given the prompt as
+    /// its argument and prints the passphrase
+    Askpass {
+        /// Absolute path of the program, `$SSH_ASKPASS` is used if not given
+        program: Option<String>
+    },
+    /// The terminal udman was started from, for headless use
+    Tty
+}
+
+impl PromptSettings {
+    fn validate(&self) -> Result<(), (String, String)> {
+        match self {
+            Self::Pinentry { program: Some(program) } | Self::Askpass { program: Some(program) } if !Path::new(program).is_absolute() => {
+                Err(("prompt.program".to_owned(), format!("'{}' must be an absolute path", program)))
+            },
+            _ => Ok(())
         }
     }
 }
 
 /// Filesystem specific options
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct FsSettings {
     /// Should filesystem be mounted automatically
     pub automount: Option<bool>,
@@ -74,6 +284,713 @@ pub struct FsSettings {
     pub run: Option<String>,
     /// Password to use if filesystem is encrypted
     pub password: Option<String>,
-    /// Path to keyfile to use if filesystem is encrypted
-    pub keyfile: Option<String>
+    /// Keyfile to use if filesystem is encrypted, either an absolute path or
+    /// a file on another device
+    pub keyfile: Option<Keyfile>,
+    /// Shell command that prints the password if filesystem is encrypted,
+    /// used when there's no `keyfile` or `password`
+    pub password_command: Option<String>,
+    /// Comma separated mount options e.g. `ro,noexec`
+    pub options: Option<String>,
+    /// Filesystem type to mount as, overriding the detected type
+    pub fstype: Option<String>,
+    /// Scripts to run for this filesystem, these replace the ones in
+    /// `[settings]`
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// How the user is asked for the passphrase if the device is encrypted
+    pub prompt: Option<PromptSettings>
+}
+
+impl FsSettings {
+    /// Fills in anything unset in these settings from `fallback`
+    pub(crate) fn merge(&self, fallback: &FsSettings) -> FsSettings {
+        FsSettings {
+            automount: self.automount.or(fallback.automount),
+            run: self.run.clone().or_else(|| fallback.run.clone()),
+            password: self.password.clone().or_else(|| fallback.password.clone()),
+            keyfile: self.keyfile.clone().or_else(|| fallback.keyfile.clone()),
+            password_command: self.password_command.clone().or_else(|| fallback.password_command.clone()),
+            options: self.options.clone().or_else(|| fallback.options.clone()),
+            fstype: self.fstype.clone().or_else(|| fallback.fstype.clone()),
+            hooks: self.hooks.merge(&fallback.hooks),
+            prompt: self.prompt.clone().or_else(|| fallback.prompt.clone())
+        }
+    }
+
+    /// Whether there's a way to unlock the device without asking the user
+    pub(crate) fn has_key(&self) -> bool {
+        self.keyfile.is_some() || self.password.is_some() || self.password_command.is_some()
+    }
+
+    fn validate(&self) -> Result<(), (String, String)> {
+        if self.password_command.as_ref().map(|command| command.trim().is_empty()).unwrap_or(false) {
+            return Err(("password_command".to_owned(), "must not be empty".to_owned()));
+        }
+
+        if let Some(path) = &self.run {
+            if !Path::new(path).is_absolute() {
+                return Err(("run".to_owned(), format!("'{}' must be an absolute path", path)));
+            }
+        }
+
+        if let Some(keyfile) = &self.keyfile {
+            keyfile.validate()?;
+        }
+
+        if let Some(prompt) = &self.prompt {
+            prompt.validate()?;
+        }
+
+        self.hooks.validate()
+    }
+}
+
+/// Where the keyfile of an encrypted device is
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Keyfile {
+    /// Absolute path of the keyfile
+    Path(String),
+    /// A keyfile on another device, e.g. a USB stick kept apart from the
+    /// encrypted drive
+    OnDevice(KeyOnDevice)
+}
+
+impl Keyfile {
+    /// Path of the keyfile if it's on this machine
+    pub(crate) fn path(&self) -> Option<&str> {
+        match self {
+            Self::Path(path) => Some(path),
+            Self::OnDevice(_) => None
+        }
+    }
+
+    fn validate(&self) -> Result<(), (String, String)> {
+        match self {
+            Self::Path(path) if !Path::new(path).is_absolute() => {
+                Err(("keyfile".to_owned(), format!("'{}' must be an absolute path", path)))
+            },
+            Self::OnDevice(key) => key.validate().map_err(|(key, reason)| (format!("keyfile.{}", key), reason)),
+            _ => Ok(())
+        }
+    }
+}
+
+/// A keyfile on the filesystem with `uuid`, which is mounted read-only to
+/// read it if it isn't mounted already and unmounted again afterwards
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct KeyOnDevice {
+    /// UUID of the filesystem the keyfile is on
+    pub uuid: String,
+    /// Path of the keyfile relative to the root of the filesystem
+    pub path: String,
+    /// Seconds to wait for the filesystem to be attached, defaults to 300
+    pub timeout: Option<u64>
+}
+
+impl KeyOnDevice {
+    fn validate(&self) -> Result<(), (String, String)> {
+        let path = Path::new(&self.path);
+
+        if self.uuid.is_empty() {
+            Err(("uuid".to_owned(), "must not be empty".to_owned()))
+        } else if self.path.is_empty() || path.is_absolute() || path.components().any(|component| component == Component::ParentDir) {
+            Err(("path".to_owned(), format!("'{}' must be a path inside the filesystem e.g. keys/backup.key", self.path)))
+        } else if self.timeout == Some(0) {
+            Err(("timeout".to_owned(), "must be at least 1 second".to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Scripts run when something happens to a device, each one is given details
+/// of the device through `UDMAN_*` environment variables
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    /// Script that decides whether and how a filesystem is mounted, it's
+    /// given the device as JSON on stdin
+    pub pre_mount: Option<String>,
+    /// Script to run when a device is attached
+    pub on_attach: Option<String>,
+    /// Script to run when a filesystem is mounted
+    pub on_mount: Option<String>,
+    /// Script to run when an encrypted device is unlocked
+    pub on_unlock: Option<String>,
+    /// Script to run when a filesystem is unmounted
+    pub on_unmount: Option<String>,
+    /// Script to run when a device is removed
+    pub on_remove: Option<String>,
+    /// Script to run when a filesystem couldn't be mounted
+    pub on_mount_failed: Option<String>,
+    /// Seconds a script can run for before it's killed, defaults to 60
+    pub timeout: Option<u64>
+}
+
+impl Hooks {
+    /// Fills in anything unset in these hooks from `fallback`
+    pub(crate) fn merge(&self, fallback: &Hooks) -> Hooks {
+        Hooks {
+            pre_mount: self.pre_mount.clone().or_else(|| fallback.pre_mount.clone()),
+            on_attach: self.on_attach.clone().or_else(|| fallback.on_attach.clone()),
+            on_mount: self.on_mount.clone().or_else(|| fallback.on_mount.clone()),
+            on_unlock: self.on_unlock.clone().or_else(|| fallback.on_unlock.clone()),
+            on_unmount: self.on_unmount.clone().or_else(|| fallback.on_unmount.clone()),
+            on_remove: self.on_remove.clone().or_else(|| fallback.on_remove.clone()),
+            on_mount_failed: self.on_mount_failed.clone().or_else(|| fallback.on_mount_failed.clone()),
+            timeout: self.timeout.or(fallback.timeout)
+        }
+    }
+
+    /// The script for a hook, if there is one
+    pub(crate) fn script(&self, hook: Hook) -> Option<&str> {
+        match hook {
+            Hook::PreMount => self.pre_mount.as_deref(),
+            Hook::Attach => self.on_attach.as_deref(),
+            Hook::Mount => self.on_mount.as_deref(),
+            Hook::Unlock => self.on_unlock.as_deref(),
+            Hook::Unmount => self.on_unmount.as_deref(),
+            Hook::Remove => self.on_remove.as_deref(),
+            Hook::MountFailed => self.on_mount_failed.as_deref()
+        }
+    }
+
+    fn validate(&self) -> Result<(), (String, String)> {
+        for hook in Hook::ALL.iter() {
+            if let Some(path) = self.script(*hook) {
+                if !Path::new(path).is_absolute() {
+                    return Err((format!("hooks.{}", hook.name()), format!("'{}' must be an absolute path", path)));
+                }
+            }
+        }
+
+        if self.timeout == Some(0) {
+            return Err(("hooks.timeout".to_owned(), "must be at least 1 second".to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Settings for every notification, each kind of notification can override
+/// them in `[notifications.kind.<kind>]`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationSettings {
+    /// Application name notifications are sent with
+    pub app_name: Option<String>,
+    /// Milliseconds before notifications expire, -1 leaves it to the
+    /// notification server and 0 never expires
+    pub timeout: Option<i32>,
+    /// Urgency of notifications, `low`, `normal` or `critical`
+    pub urgency: Option<String>,
+    /// Icon name or path, defaults to the icon UDisks2 suggests for the device
+    pub icon: Option<String>,
+    /// Settings for each kind of notification e.g. `mount_success`
+    pub kind: HashMap<String, NoticeSettings>,
+    /// Where notifications are delivered, desktop popups if none are given
+    pub sink: Vec<SinkSettings>
+}
+
+/// Somewhere notifications can be delivered to
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SinkSettings {
+    /// Desktop popups from a freedesktop notification server
+    Freedesktop,
+    /// Lines written to stderr
+    Log,
+    /// Lines of JSON appended to a file
+    File {
+        /// Absolute path of the file
+        path: PathBuf
+    },
+    /// A command run for each notification
+    Command {
+        /// Absolute path of the command
+        command: String
+    }
+}
+
+/// Settings for one kind of notification
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NoticeSettings {
+    /// Whether this kind of notification is shown
+    pub enabled: Option<bool>,
+    /// Summary template, placeholders such as `{label}` are filled in
+    pub summary: Option<String>,
+    /// Body template, placeholders such as `{mount_path}` are filled in
+    pub body: Option<String>,
+    /// Urgency, `low`, `normal` or `critical`
+    pub urgency: Option<String>,
+    /// Milliseconds before the notification expires
+    pub timeout: Option<i32>,
+    /// Icon name or path
+    pub icon: Option<String>
+}
+
+impl NotificationSettings {
+    fn validate(&self) -> Result<(), (String, String)> {
+        validate_urgency("urgency", &self.urgency)?;
+
+        for sink in &self.sink {
+            let path = match sink {
+                SinkSettings::File { path } => path.as_path(),
+                SinkSettings::Command { command } => Path::new(command),
+                _ => continue
+            };
+
+            if !path.is_absolute() {
+                return Err(("sink".to_owned(), format!("'{}' must be an absolute path", path.display())));
+            }
+        }
+
+        for (kind, settings) in &self.kind {
+            if !notices::KINDS.contains(&kind.as_str()) {
+                return Err((format!("kind.{}", kind), format!("unknown kind of notification, expected one of {}", notices::KINDS.join(", "))));
+            }
+
+            validate_urgency(&format!("kind.{}.urgency", kind), &settings.urgency)?;
+
+            for (key, template) in &[("summary", &settings.summary), ("body", &settings.body)] {
+                if let Some(template) = template {
+                    validate_template(template).map_err(|reason| (format!("kind.{}.{}", kind, key), reason))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_urgency(key: &str, urgency: &Option<String>) -> Result<(), (String, String)> {
+    match urgency {
+        Some(urgency) if Urgency::from_name(urgency).is_none() => {
+            Err((key.to_owned(), format!("'{}' is not an urgency, expected low, normal or critical", urgency)))
+        },
+        _ => Ok(())
+    }
+}
+
+/// Checks that every `{placeholder}` in a template is one udman fills in
+fn validate_template(template: &str) -> Result<(), String> {
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}').ok_or_else(|| format!("unclosed '{{' in '{}'", template))?;
+        let placeholder = &rest[start + 1..start + end];
+
+        if !notices::PLACEHOLDERS.contains(&placeholder) {
+            return Err(format!("unknown placeholder '{{{}}}', expected one of {}", placeholder, notices::PLACEHOLDERS.join(", ")));
+        }
+
+        rest = &rest[start + end + 1..];
+    }
+
+    Ok(())
+}
+
+/// Where events are published as JSON, they're always streamed to clients
+/// of the event socket
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventSettings {
+    /// URLs each event is POSTed to
+    pub webhook: Vec<String>,
+    /// Path of the event socket, defaults to
+    /// `$XDG_RUNTIME_DIR/udman/events.sock`
+    pub socket: Option<PathBuf>,
+    /// How many events can wait for each webhook or the socket before new
+    /// ones are dropped
+    pub queue_size: Option<usize>,
+    /// How many times a webhook is retried before an event is given up on
+    pub retries: Option<u32>
+}
+
+impl EventSettings {
+    fn validate(&self) -> Result<(), (String, String)> {
+        if let Some(url) = self.webhook.iter().find(|url| !url.starts_with("http://")) {
+            return Err(("webhook".to_owned(), format!("'{}' must be an http:// URL", url)));
+        }
+
+        if let Some(socket) = self.socket.as_ref().filter(|socket| !socket.is_absolute()) {
+            return Err(("socket".to_owned(), format!("'{}' must be an absolute path", socket.display())));
+        }
+
+        if self.queue_size == Some(0) {
+            return Err(("queue_size".to_owned(), "must be at least 1".to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
+/// The freedesktop Secret Service passphrases are looked up in before the
+/// user is asked for them, and saved to if the user chooses to
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyringSettings {
+    /// Whether the keyring is used, defaults to true
+    pub enabled: Option<bool>,
+    /// D-Bus address of the bus the Secret Service is on, defaults to the
+    /// session bus
+    pub address: Option<String>
+}
+
+impl KeyringSettings {
+    fn validate(&self) -> Result<(), (String, String)> {
+        match &self.address {
+            Some(address) if !address.contains(':') => {
+                Err(("address".to_owned(), format!("'{}' isn't a D-Bus address e.g. unix:path=/run/user/1000/bus", address)))
+            },
+            _ => Ok(())
+        }
+    }
+}
+
+/// Settings that apply to every filesystem of a type
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FstypeSettings {
+    /// Comma separated mount options e.g. `ro,noexec`
+    pub options: Option<String>
+}
+
+/// Settings that apply to every device matching the rule
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Properties a device needs to have for the rule to apply
+    #[serde(rename = "match")]
+    pub matches: RuleMatch,
+    /// Settings for matching devices
+    pub settings: FsSettings
+}
+
+/// Properties to match devices against. Text properties are glob patterns,
+/// a device has to match every property that's given
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuleMatch {
+    /// Filesystem UUID
+    pub uuid: Option<String>,
+    /// Filesystem label
+    pub label: Option<String>,
+    /// Filesystem type as reported by UDisks2 e.g. vfat, ext4, ntfs
+    pub fstype: Option<String>,
+    /// Smallest size of the device e.g. 512M, 16G
+    pub min_size: Option<String>,
+    /// Largest size of the device e.g. 512M, 16G
+    pub max_size: Option<String>,
+    /// Vendor of the drive
+    pub vendor: Option<String>,
+    /// Model of the drive
+    pub model: Option<String>,
+    /// Serial number of the drive
+    pub serial: Option<String>,
+    /// Bus the drive is connected through e.g. usb, sdio
+    pub bus: Option<String>
+}
+
+impl RuleMatch {
+    /// Checks whether a device and the drive it belongs to match
+    pub(crate) fn is_match(&self, device: &Block, drive: Option<&Drive>) -> bool {
+        let drive_property = |property: fn(&Drive) -> &Option<String>| drive.and_then(|drive| property(drive).as_deref());
+
+        glob_match(&self.uuid, device.uuid.as_deref())
+            && glob_match(&self.label, device.label.as_deref())
+            && glob_match(&self.fstype, device.id_type.as_deref())
+            && glob_match(&self.vendor, drive_property(|drive| &drive.vendor))
+            && glob_match(&self.model, drive_property(|drive| &drive.model))
+            && glob_match(&self.serial, drive_property(|drive| &drive.serial))
+            && glob_match(&self.bus, drive_property(|drive| &drive.connection_bus))
+            && self.min_size.as_deref().and_then(parse_size).map(|min_size| device.size >= min_size).unwrap_or(true)
+            && self.max_size.as_deref().and_then(parse_size).map(|max_size| device.size <= max_size).unwrap_or(true)
+    }
+
+    fn validate(&self) -> Result<(), (String, String)> {
+        let patterns = [
+            ("uuid", &self.uuid), ("label", &self.label), ("fstype", &self.fstype), ("vendor", &self.vendor),
+            ("model", &self.model), ("serial", &self.serial), ("bus", &self.bus)
+        ];
+
+        for (key, pattern) in &patterns {
+            if let Some(pattern) = pattern {
+                if let Err(e) = Pattern::new(pattern) {
+                    return Err((key.to_string(), format!("'{}' is not a valid pattern: {}", pattern, e)));
+                }
+            }
+        }
+
+        for (key, size) in &[("min_size", &self.min_size), ("max_size", &self.max_size)] {
+            if let Some(size) = size {
+                if parse_size(size).is_none() {
+                    return Err((key.to_string(), format!("'{}' is not a size, expected something like 512M or 16G", size)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A missing pattern matches anything, otherwise the value has to be present
+/// and match the pattern
+fn glob_match(pattern: &Option<String>, value: Option<&str>) -> bool {
+    match (pattern, value) {
+        (Some(pattern), Some(value)) => Pattern::new(pattern).map(|pattern| pattern.matches(value)).unwrap_or(false),
+        (Some(_), None) => false,
+        (None, _) => true
+    }
+}
+
+/// Parses sizes such as 1000, 512K, 16G or 2TB into bytes. Units are powers
+/// of 1000, the way sizes are shown and drives are sold
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim().to_uppercase();
+    let size = size.trim_end_matches('B');
+    let (number, multiplier) = match size.chars().last()? {
+        'K' => (&size[..size.len() - 1], 1_000u64),
+        'M' => (&size[..size.len() - 1], 1_000_000),
+        'G' => (&size[..size.len() - 1], 1_000_000_000),
+        'T' => (&size[..size.len() - 1], 1_000_000_000_000),
+        _ => (size, 1)
+    };
+
+    number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses and validates a config the way `Config::load` does
+    fn parse(config: &str) -> Config {
+        let config: Config = toml::from_str(config).unwrap();
+        config.validate().unwrap();
+
+        config
+    }
+
+    /// A config file of the test's own, in a directory that doesn't exist
+    /// yet. The directory is removed when it's dropped
+    struct ConfigFile(PathBuf);
+
+    impl ConfigFile {
+        fn new(name: &str) -> Self {
+            ConfigFile(std::env::temp_dir().join(format!("udman-{}-{}", name, std::process::id())).join("config.toml"))
+        }
+
+        fn write(&self, contents: &str) {
+            fs::create_dir_all(self.0.parent().unwrap()).unwrap();
+            fs::write(&self.0, contents).unwrap();
+        }
+
+        fn read(&self) -> String {
+            fs::read_to_string(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            fs::remove_dir_all(self.0.parent().unwrap()).ok();
+        }
+    }
+
+    fn stick() -> (Block, Drive) {
+        let device = Block {
+            uuid: Some("1234-ABCD".to_owned()),
+            label: Some("BACKUP".to_owned()),
+            id_type: Some("vfat".to_owned()),
+            size: 16_000_000_000,
+            ..Block::default()
+        };
+        let drive = Drive {
+            vendor: Some("SanDisk".to_owned()),
+            model: Some("Cruzer".to_owned()),
+            connection_bus: Some("usb".to_owned()),
+            ..Drive::default()
+        };
+
+        (device, drive)
+    }
+
+    #[test]
+    fn sizes_are_in_powers_of_1000() {
+        assert_eq!(parse_size("1000"), Some(1000));
+        assert_eq!(parse_size("512K"), Some(512_000));
+        assert_eq!(parse_size("16G"), Some(16_000_000_000));
+        assert_eq!(parse_size("16gb"), Some(16_000_000_000));
+        assert_eq!(parse_size(" 2 TB "), Some(2_000_000_000_000));
+    }
+
+    #[test]
+    fn sizes_that_cant_be_parsed() {
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("16GiB"), None);
+        assert_eq!(parse_size("-1M"), None);
+        assert_eq!(parse_size("99999999T"), None);
+    }
+
+    #[test]
+    fn rule_matches_every_property_given() {
+        let (device, drive) = stick();
+        let rule = RuleMatch {
+            label: Some("BACK*".to_owned()),
+            vendor: Some("SanDisk".to_owned()),
+            bus: Some("usb".to_owned()),
+            max_size: Some("16G".to_owned()),
+            ..RuleMatch::default()
+        };
+
+        assert!(rule.is_match(&device, Some(&drive)));
+        assert!(!RuleMatch { fstype: Some("ext4".to_owned()), ..rule }.is_match(&device, Some(&drive)));
+    }
+
+    #[test]
+    fn rule_checks_sizes() {
+        let (device, drive) = stick();
+        let min_size = |size: &str| RuleMatch { min_size: Some(size.to_owned()), ..RuleMatch::default() };
+        let max_size = |size: &str| RuleMatch { max_size: Some(size.to_owned()), ..RuleMatch::default() };
+
+        assert!(min_size("16G").is_match(&device, Some(&drive)));
+        assert!(!min_size("17G").is_match(&device, Some(&drive)));
+        assert!(max_size("16G").is_match(&device, Some(&drive)));
+        assert!(!max_size("15G").is_match(&device, Some(&drive)));
+    }
+
+    #[test]
+    fn rule_on_drive_properties_needs_a_drive() {
+        let (device, _) = stick();
+
+        assert!(RuleMatch::default().is_match(&device, None));
+        assert!(!RuleMatch { vendor: Some("*".to_owned()), ..RuleMatch::default() }.is_match(&device, None));
+    }
+
+    #[test]
+    fn uuid_settings_take_precedence_over_rules() {
+        let (device, drive) = stick();
+        let config = parse(r#"
+            [uuid.1234-ABCD]
+            automount = true
+            options = "noexec"
+
+            [[rule]]
+            match = { vendor = "SanDisk" }
+            settings = { automount = false, options = "ro", run = "/usr/local/bin/backup.sh" }
+        "#);
+
+        let settings = config.resolve(&device, Some(&drive));
+
+        assert_eq!(settings.automount, Some(true));
+        assert_eq!(settings.options.as_deref(), Some("noexec"));
+        assert_eq!(settings.run.as_deref(), Some("/usr/local/bin/backup.sh"));
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let (device, drive) = stick();
+        let config = parse(r#"
+            [[rule]]
+            match = { vendor = "Kingston" }
+            settings = { options = "sync" }
+
+            [[rule]]
+            match = { bus = "usb" }
+            settings = { options = "ro" }
+
+            [[rule]]
+            match = { label = "BACKUP" }
+            settings = { options = "noexec" }
+        "#);
+
+        assert_eq!(config.resolve(&device, Some(&drive)).options.as_deref(), Some("ro"));
+    }
+
+    #[test]
+    fn unset_settings_fall_back_to_defaults() {
+        let (device, drive) = stick();
+        let config = parse(r#"
+            [settings]
+            automount = true
+
+            [fstype.vfat]
+            options = "flush"
+
+            [[rule]]
+            match = { bus = "usb" }
+            settings = { run = "/usr/local/bin/backup.sh" }
+        "#);
+
+        let settings = config.resolve(&device, Some(&drive));
+
+        assert!(settings.automount.unwrap_or(config.settings.automount));
+        assert_eq!(settings.options.as_deref(), Some("flush"));
+    }
+
+    #[test]
+    fn remembering_keeps_comments_and_formatting() {
+        let file = ConfigFile::new("remember-comments");
+        file.write("# Sticks I use\n[uuid.1234-ABCD]\nautomount = false # not this one\nrun   = \"/usr/local/bin/backup.sh\"\n");
+
+        Config::remember_automount(&file.0, "1234-ABCD", true).unwrap();
+
+        assert_eq!(file.read(), "# Sticks I use\n[uuid.1234-ABCD]\nautomount = true # not this one\nrun   = \"/usr/local/bin/backup.sh\"\n");
+    }
+
+    #[test]
+    fn remembering_adds_a_section_for_a_new_device() {
+        let file = ConfigFile::new("remember-new");
+        file.write("[settings]\n# Ask first\nautomount = false\n");
+
+        Config::remember_automount(&file.0, "1234-ABCD", true).unwrap();
+
+        assert_eq!(file.read(), "[settings]\n# Ask first\nautomount = false\n\n[uuid.1234-ABCD]\nautomount = true\n");
+        assert_eq!(Config::load(&file.0).unwrap().get_uuid_settings("1234-ABCD").unwrap().automount, Some(true));
+    }
+
+    #[test]
+    fn remembering_creates_the_config_file() {
+        let file = ConfigFile::new("remember-create");
+
+        Config::remember_automount(&file.0, "1234-ABCD", false).unwrap();
+
+        assert_eq!(file.read(), "[uuid.1234-ABCD]\nautomount = false\n");
+    }
+
+    #[test]
+    fn templates_use_known_placeholders() {
+        assert_eq!(validate_template("{label} mounted at {mount_path}"), Ok(()));
+        assert_eq!(validate_template("No placeholders"), Ok(()));
+        assert!(validate_template("{labl}").unwrap_err().contains("unknown placeholder '{labl}'"));
+        assert!(validate_template("{label").unwrap_err().contains("unclosed"));
+    }
+
+    #[test]
+    fn bad_template_is_reported_with_its_key() {
+        let file = ConfigFile::new("template");
+        file.write("[notifications.kind.mount_success]\nbody = \"{where}\"\n");
+
+        match Config::load(&file.0) {
+            Err(ConfigError::InvalidValue { key, .. }) => assert_eq!(key, "notifications.kind.mount_success.body"),
+            other => panic!("expected an invalid value, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn relative_run_is_relative_to_the_config_file() {
+        let file = ConfigFile::new("relative-run");
+        file.write("[uuid.1234-ABCD]\nrun = \"backup.sh\"\n\n[[rule]]\nmatch = { label = \"STICK\" }\nsettings = { run = \"/usr/local/bin/sync.sh\" }\n");
+
+        let config = Config::load(&file.0).unwrap();
+        let dir = file.0.parent().unwrap();
+
+        assert_eq!(config.get_uuid_settings("1234-ABCD").unwrap().run, Some(dir.join("backup.sh").to_string_lossy().into_owned()));
+        assert_eq!(config.rule[0].settings.run.as_deref(), Some("/usr/local/bin/sync.sh"));
+    }
 }
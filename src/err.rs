@@ -1,6 +1,9 @@
 use std::fmt;
 use std::error;
+use std::io;
+use std::path::{Path, PathBuf};
 use crate::notifications;
+use crate::processes::Process;
 
 #[derive(Debug)]
 pub enum MounterError {
@@ -8,6 +11,20 @@ pub enum MounterError {
     UnlockFailed(dbus::Error),
     UnreadableKeyFile(std::io::Error),
     NotifierError(notifications::NotifierError),
+    UnknownDrive(String),
+    UnmountFailed(String, dbus::Error),
+    DeviceBusy(String, Vec<Process>),
+    LockFailed(String, dbus::Error),
+    EjectFailed(String, dbus::Error),
+    NotPoweredOff(String),
+    UnknownDevice(String),
+    NotFilesystem(String),
+    NotEncrypted(String),
+    MountFailed(String, String),
+    KeyDeviceMissing(String),
+    TimedOut(String),
+    Cancelled,
+    DBusError(dbus::Error),
 }
 
 impl fmt::Display for MounterError {
@@ -17,6 +34,29 @@ impl fmt::Display for MounterError {
             Self::UnlockFailed(e) => write!(f, "Unlock Failed: {}", e.message().unwrap_or("D-Bus error")),
             Self::UnreadableKeyFile(e) => write!(f, "Couldn't read provided keyfile: {}", e),
             Self::NotifierError(e) => write!(f, "{}", e),
+            Self::UnknownDrive(drive) => write!(f, "No drive found matching {}", drive),
+            Self::UnmountFailed(mount_path, e) => write!(f, "Couldn't unmount {}: {}", mount_path, e.message().unwrap_or("D-Bus error")),
+            Self::DeviceBusy(mount_path, processes) => {
+                write!(f, "Couldn't unmount {}, it's busy", mount_path)?;
+
+                if !processes.is_empty() {
+                    let processes: Vec<String> = processes.iter().map(Process::to_string).collect();
+                    write!(f, " in {}", processes.join(", "))?;
+                }
+
+                write!(f, ". Close them or use `udman eject --lazy`")
+            },
+            Self::LockFailed(device, e) => write!(f, "Couldn't lock {}: {}", device, e.message().unwrap_or("D-Bus error")),
+            Self::EjectFailed(drive, e) => write!(f, "Couldn't power off {}: {}", drive, e.message().unwrap_or("D-Bus error")),
+            Self::NotPoweredOff(drive) => write!(f, "{} was unmounted and locked but can't be powered off", drive),
+            Self::UnknownDevice(device) => write!(f, "No device found matching {}", device),
+            Self::NotFilesystem(device) => write!(f, "{} doesn't hold a filesystem that can be mounted", device),
+            Self::NotEncrypted(device) => write!(f, "{} isn't an encrypted device", device),
+            Self::MountFailed(device, reason) => write!(f, "Couldn't mount {}: {}", device, reason),
+            Self::KeyDeviceMissing(uuid) => write!(f, "The device holding the key, {}, isn't attached", uuid),
+            Self::TimedOut(what) => write!(f, "Timed out {}", what),
+            Self::Cancelled => write!(f, "Cancelled as the device was removed"),
+            Self::DBusError(e) => write!(f, "{}", e.message().unwrap_or("D-Bus error")),
         }
     }
 }
@@ -28,6 +68,20 @@ impl error::Error for MounterError {
             Self::UnlockFailed(e) => Some(e),
             Self::UnreadableKeyFile(e) => Some(e),
             Self::NotifierError(e) => Some(e),
+            Self::UnknownDrive(_) => None,
+            Self::UnmountFailed(_, e) => Some(e),
+            Self::DeviceBusy(_, _) => None,
+            Self::LockFailed(_, e) => Some(e),
+            Self::EjectFailed(_, e) => Some(e),
+            Self::NotPoweredOff(_) => None,
+            Self::UnknownDevice(_) => None,
+            Self::NotFilesystem(_) => None,
+            Self::NotEncrypted(_) => None,
+            Self::MountFailed(_, _) => None,
+            Self::KeyDeviceMissing(_) => None,
+            Self::TimedOut(_) => None,
+            Self::Cancelled => None,
+            Self::DBusError(e) => Some(e),
         }
     }
 }
@@ -37,3 +91,137 @@ impl From<notifications::NotifierError> for MounterError {
         MounterError::NotifierError(err)
     }
 }
+
+/// Errors that can occur while loading or updating the configuration file
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file couldn't be read
+    Io(PathBuf, io::Error),
+    /// The file couldn't be written
+    Write(PathBuf, io::Error),
+    /// The file isn't valid TOML or a value has the wrong type
+    Syntax {
+        /// Path to the config file
+        path: PathBuf,
+        /// Description of the problem
+        message: String,
+        /// Line and column of the problem, starting from 1
+        position: Option<(usize, usize)>
+    },
+    /// An option was given that udman doesn't know about, usually a typo
+    UnknownKey {
+        /// Path to the config file
+        path: PathBuf,
+        /// Description of the problem including the expected keys
+        message: String,
+        /// Line and column of the problem, starting from 1
+        position: Option<(usize, usize)>
+    },
+    /// A value was the right type but can't be used
+    InvalidValue {
+        /// Path to the config file
+        path: PathBuf,
+        /// Full key of the offending value e.g. `uuid.<uuid>.keyfile`
+        key: String,
+        /// Why the value can't be used
+        reason: String
+    }
+}
+
+impl ConfigError {
+    pub(crate) fn from_toml(path: &Path, err: toml::de::Error) -> Self {
+        let mut message = err.to_string();
+
+        // The position is reported separately so drop it from the message
+        if let Some(index) = message.rfind(" at line ") {
+            message.truncate(index);
+        }
+
+        let position = err.line_col().map(|(line, column)| (line + 1, column + 1));
+
+        if message.starts_with("unknown field") {
+            Self::UnknownKey { path: path.to_owned(), message, position }
+        } else {
+            Self::Syntax { path: path.to_owned(), message, position }
+        }
+    }
+
+    pub(crate) fn from_toml_edit(path: &Path, contents: &str, err: toml_edit::TomlError) -> Self {
+        // Spans are byte offsets into the file, count lines and columns from 1
+        let position = err.span().map(|span| {
+            let before = &contents[..span.start.min(contents.len())];
+            let line_start = before.rfind('\n').map(|index| index + 1).unwrap_or(0);
+
+            (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+        });
+
+        Self::Syntax { path: path.to_owned(), message: err.message().trim_end().lines().collect::<Vec<_>>().join(", "), position }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "Couldn't read {}: {}", path.display(), e),
+            Self::Write(path, e) => write!(f, "Couldn't write {}: {}", path.display(), e),
+            Self::Syntax { path, message, position } | Self::UnknownKey { path, message, position } => {
+                match position {
+                    Some((line, column)) => write!(f, "{}:{}:{}: {}", path.display(), line, column, message),
+                    None => write!(f, "{}: {}", path.display(), message)
+                }
+            },
+            Self::InvalidValue { path, key, reason } => write!(f, "{}: invalid value for `{}`: {}", path.display(), key, reason),
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(_, e) | Self::Write(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn load(contents: &str) -> ConfigError {
+        let err = toml::from_str::<Config>(contents).unwrap_err();
+
+        ConfigError::from_toml(Path::new("config.toml"), err)
+    }
+
+    #[test]
+    fn syntax_error_has_its_position() {
+        match load("[settings]\nautomount = true\nrun = \"backup.sh\n") {
+            ConfigError::Syntax { message, position, .. } => {
+                assert_eq!(position, Some((3, 17)));
+                assert!(!message.contains(" at line "), "{}", message);
+            },
+            other => panic!("expected a syntax error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn unknown_key_has_its_position() {
+        let err = load("[settings]\nautomount = true\n\n[uuid.1234-ABCD]\nautomuont = true\n");
+
+        assert!(matches!(&err, ConfigError::UnknownKey { position: Some((4, 1)), .. }), "{:?}", err);
+        assert!(err.to_string().starts_with("config.toml:4:1: unknown field `automuont`"), "{}", err);
+    }
+
+    #[test]
+    fn edit_error_has_its_position() {
+        let contents = "[settings]\nautomount = = true\n";
+        let err = contents.parse::<toml_edit::Document>().unwrap_err();
+
+        match ConfigError::from_toml_edit(Path::new("config.toml"), contents, err) {
+            ConfigError::Syntax { position, .. } => assert_eq!(position, Some((2, 13))),
+            other => panic!("expected a syntax error, got {:?}", other)
+        }
+    }
+}
@@ -0,0 +1,352 @@
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+use serde::Serialize;
+use crate::config::EventSettings;
+use crate::incidents;
+use crate::notices::Details;
+use crate::udisks2::devices::Block;
+
+/// How many events can wait for each webhook or the socket before new ones
+/// are dropped, if no queue size is configured
+pub const DEFAULT_QUEUE_SIZE: usize = 100;
+
+/// How many times a webhook is retried if no number is configured
+pub const DEFAULT_RETRIES: u32 = 5;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Something that happened to a device, as it's published
+#[derive(Debug, Serialize)]
+pub struct Event<'a> {
+    pub event: &'static str,
+    pub time: String,
+    pub object_path: Option<&'a str>,
+    pub device: Option<&'a str>,
+    pub uuid: Option<&'a str>,
+    pub label: Option<&'a str>,
+    pub fstype: Option<&'a str>,
+    pub size: Option<u64>,
+    pub mount_path: Option<&'a str>,
+    pub drive: Option<&'a str>,
+    pub error: Option<&'a str>,
+    /// What udman decided to do with the device, for `policy` events
+    pub decision: Option<&'static str>
+}
+
+impl<'a> Event<'a> {
+    pub fn new(event: &'static str, device: Option<&'a Block>, details: &Details<'a>) -> Self {
+        Event {
+            event,
+            time: incidents::utc_timestamp(),
+            object_path: device.map(|device| &*device.object_path),
+            device: details.device.or_else(|| device.map(|device| device.device.as_str())),
+            uuid: device.and_then(|device| device.uuid.as_deref()),
+            label: details.label,
+            fstype: details.fstype,
+            size: details.size,
+            mount_path: details.mount_path,
+            drive: details.drive,
+            error: details.error,
+            decision: None
+        }
+    }
+}
+
+/// Location of the event socket, `$XDG_RUNTIME_DIR/udman/events.sock`
+/// unless the config says otherwise
+pub fn socket_path(settings: &EventSettings) -> Option<PathBuf> {
+    settings.socket.to_owned().or_else(|| {
+        xdg::BaseDirectories::with_prefix("udman").ok()?
+            .place_runtime_file("events.sock").ok()
+    })
+}
+
+/// Publishes device events as JSON to webhooks and to subscribers on a Unix
+/// socket. Delivery happens on background threads, each fed by a bounded
+/// queue so a slow or unreachable subscriber can't hold up udman or use
+/// up memory
+#[derive(Debug, Default)]
+pub struct Publisher {
+    settings: Option<EventSettings>,
+    queues: Vec<SyncSender<String>>,
+    subscribers: Vec<SyncSender<String>>
+}
+
+impl Publisher {
+    pub fn new(settings: &EventSettings) -> Self {
+        let mut publisher = Publisher::default();
+
+        publisher.configure(settings);
+        publisher
+    }
+
+    /// Starts publishing to the configured webhooks and socket, stopping
+    /// whatever was running for the old settings
+    pub fn configure(&mut self, settings: &EventSettings) {
+        if self.settings.as_ref() == Some(settings) {
+            return;
+        }
+
+        // Dropping the queues stops their threads once they're empty
+        self.queues.clear();
+        self.settings = Some(settings.to_owned());
+
+        let queue_size = settings.queue_size.unwrap_or(DEFAULT_QUEUE_SIZE);
+        let retries = settings.retries.unwrap_or(DEFAULT_RETRIES);
+
+        for url in &settings.webhook {
+            let (queue, events) = mpsc::sync_channel(queue_size);
+            let url = url.to_owned();
+
+            thread::spawn(move || post_events(&url, events, retries));
+            self.queues.push(queue);
+        }
+
+        if let Some(path) = socket_path(settings) {
+            match listen(&path) {
+                Ok(listener) => {
+                    let (queue, events) = mpsc::sync_channel(queue_size);
+
+                    thread::spawn(move || broadcast_events(listener, events));
+                    self.queues.push(queue);
+                },
+                Err(e) => eprintln!("Could not listen on {}: {}", path.display(), e)
+            }
+        }
+    }
+
+    /// Events as they're published, for use within udman. Unlike webhooks
+    /// and the socket, subscriptions last through config reloads
+    pub fn subscribe(&mut self) -> Receiver<String> {
+        let (queue, events) = mpsc::sync_channel(DEFAULT_QUEUE_SIZE);
+
+        self.subscribers.push(queue);
+        events
+    }
+
+    /// Queues an event for every webhook, the socket and subscribers, it's
+    /// dropped for any that are too far behind
+    pub fn publish(&self, event: &Event) {
+        if self.queues.is_empty() && self.subscribers.is_empty() {
+            return;
+        }
+
+        let json = match serde_json::to_string(event) {
+            Ok(json) => json,
+            Err(_) => return
+        };
+
+        for queue in self.queues.iter().chain(self.subscribers.iter()) {
+            if let Err(TrySendError::Full(_)) = queue.try_send(json.to_owned()) {
+                eprintln!("Event queue is full, dropping {} event", event.event);
+            }
+        }
+    }
+}
+
+/// POSTs each event to a webhook, retrying with exponential backoff if the
+/// server can't be reached or has an error of its own. Events are sent in
+/// order so a retry holds up the events behind it
+fn post_events(url: &str, events: Receiver<String>, retries: u32) {
+    for json in events {
+        let mut backoff = Duration::from_secs(1);
+
+        for attempt in 0..=retries {
+            let error = match ureq::post(url).timeout(HTTP_TIMEOUT).set("Content-Type", "application/json").send_string(&json) {
+                Ok(_) => break,
+                // The server understood and refused the event, sending it
+                // again won't help
+                Err(ureq::Error::Status(status, _)) if status < 500 && status != 429 => {
+                    eprintln!("Webhook {} rejected event with status {}", url, status);
+                    break;
+                },
+                Err(e) => e
+            };
+
+            if attempt == retries {
+                eprintln!("Giving up on webhook {} after {} attempts: {}", url, attempt + 1, error);
+                break;
+            }
+
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+fn listen(path: &Path) -> io::Result<UnixListener> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // A socket left behind by an earlier run would stop the bind, anything
+    // else there is left alone as the path is more likely a mistake
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "something other than a socket is there")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e)
+    }
+
+    let listener = UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+
+    Ok(listener)
+}
+
+/// Writes each event as a line of JSON to every client connected to the
+/// socket, clients that can't keep up are disconnected
+fn broadcast_events(listener: UnixListener, events: Receiver<String>) {
+    let mut subscribers: Vec<UnixStream> = Vec::new();
+
+    loop {
+        let event = events.recv_timeout(Duration::from_millis(200));
+
+        while let Ok((stream, _)) = listener.accept() {
+            if stream.set_nonblocking(false).is_ok() && stream.set_write_timeout(Some(Duration::from_secs(1))).is_ok() {
+                subscribers.push(stream);
+            }
+        }
+
+        match event {
+            Ok(json) => subscribers.retain(|mut subscriber| writeln!(subscriber, "{}", json).is_ok()),
+            Err(RecvTimeoutError::Timeout) => (),
+            // The socket file is left for the next listener to replace, it
+            // may already have been
+            Err(RecvTimeoutError::Disconnected) => return
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    /// A webhook that answers with each status in turn, reporting the body
+    /// of every request and when it arrived
+    fn webhook(statuses: Vec<u16>) -> (String, Receiver<(String, Instant)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        let (sender, requests) = mpsc::channel();
+
+        thread::spawn(move || {
+            for (stream, status) in listener.incoming().zip(statuses) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+
+                    match header.trim().to_lowercase().strip_prefix("content-length:") {
+                        Some(value) => length = value.trim().parse().unwrap(),
+                        None if header.trim().is_empty() => break,
+                        None => ()
+                    }
+                }
+
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                sender.send((String::from_utf8(body).unwrap(), Instant::now())).unwrap();
+
+                write!(stream, "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn post(url: String, events: &[&str], retries: u32) {
+        let (queue, queued) = mpsc::sync_channel(events.len());
+
+        for event in events {
+            queue.send(event.to_string()).unwrap();
+        }
+
+        drop(queue);
+        post_events(&url, queued, retries);
+    }
+
+    /// A path for a socket in a directory of its own, removed with everything
+    /// in it when dropped
+    struct SocketPath(PathBuf);
+
+    impl SocketPath {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("udman-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+
+            SocketPath(dir.join("events.sock"))
+        }
+    }
+
+    impl Drop for SocketPath {
+        fn drop(&mut self) {
+            fs::remove_dir_all(self.0.parent().unwrap()).ok();
+        }
+    }
+
+    #[test]
+    fn webhook_is_retried_with_backoff() {
+        let (url, requests) = webhook(vec![500, 503, 200]);
+
+        post(url, &["{\"event\":\"mounted\"}"], 5);
+
+        let requests: Vec<(String, Instant)> = requests.try_iter().collect();
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|(body, _)| body == "{\"event\":\"mounted\"}"));
+        assert!(requests[1].1 - requests[0].1 >= Duration::from_secs(1));
+        assert!(requests[2].1 - requests[1].1 >= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn webhook_is_given_up_on_after_retries() {
+        let (url, requests) = webhook(vec![500, 500, 200]);
+
+        post(url, &["{\"event\":\"mounted\"}", "{\"event\":\"unmounted\"}"], 1);
+
+        let bodies: Vec<String> = requests.try_iter().map(|(body, _)| body).collect();
+        assert_eq!(bodies, ["{\"event\":\"mounted\"}", "{\"event\":\"mounted\"}", "{\"event\":\"unmounted\"}"]);
+    }
+
+    #[test]
+    fn rejected_event_is_not_retried() {
+        let (url, requests) = webhook(vec![400, 200]);
+        let start = Instant::now();
+
+        post(url, &["{\"event\":\"mounted\"}", "{\"event\":\"unmounted\"}"], 5);
+
+        let bodies: Vec<String> = requests.try_iter().map(|(body, _)| body).collect();
+        assert_eq!(bodies, ["{\"event\":\"mounted\"}", "{\"event\":\"unmounted\"}"]);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn socket_left_behind_is_replaced() {
+        let path = SocketPath::new("stale-socket");
+        drop(UnixListener::bind(&path.0).unwrap());
+
+        let _listener = listen(&path.0).unwrap();
+        assert!(UnixStream::connect(&path.0).is_ok());
+    }
+
+    #[test]
+    fn file_at_socket_path_is_kept() {
+        let path = SocketPath::new("regular-file");
+        fs::write(&path.0, "not a socket").unwrap();
+
+        assert_eq!(listen(&path.0).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&path.0).unwrap(), "not a socket");
+    }
+}
@@ -0,0 +1,199 @@
+use std::process::Output;
+use std::time::Duration;
+use serde::Serialize;
+use crate::udisks2::devices::{Block, Drive};
+
+/// How long scripts can run for if no timeout is configured
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Points in a device's life that a script can be run at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hook {
+    PreMount,
+    Attach,
+    Mount,
+    Unlock,
+    Unmount,
+    Remove,
+    MountFailed
+}
+
+impl Hook {
+    pub const ALL: [Hook; 7] = [Hook::PreMount, Hook::Attach, Hook::Mount, Hook::Unlock, Hook::Unmount, Hook::Remove, Hook::MountFailed];
+
+    /// Name of the hook as it's written in the config file
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::PreMount => "pre_mount",
+            Hook::Attach => "on_attach",
+            Hook::Mount => "on_mount",
+            Hook::Unlock => "on_unlock",
+            Hook::Unmount => "on_unmount",
+            Hook::Remove => "on_remove",
+            Hook::MountFailed => "on_mount_failed"
+        }
+    }
+}
+
+/// Environment variables describing the device a hook is run for. Anything
+/// that isn't known is set to an empty string so scripts don't pick up a
+/// value from udman's own environment
+pub fn environment(hook: Hook, device: &Block, drive: Option<&Drive>, mount_path: Option<&str>, error: Option<&str>) -> Vec<(&'static str, String)> {
+    vec!(
+        ("UDMAN_HOOK", hook.name().to_owned()),
+        ("UDMAN_DEVICE", device.device.to_owned()),
+        ("UDMAN_UUID", device.uuid.to_owned().unwrap_or_default()),
+        ("UDMAN_LABEL", device.label.to_owned().unwrap_or_default()),
+        ("UDMAN_MOUNT_PATH", mount_path.unwrap_or_default().to_owned()),
+        ("UDMAN_FSTYPE", device.id_type.to_owned().unwrap_or_default()),
+        ("UDMAN_DRIVE_SERIAL", drive.and_then(|drive| drive.serial.to_owned()).unwrap_or_default()),
+        ("UDMAN_ERROR", error.unwrap_or_default().to_owned())
+    )
+}
+
+/// Writes what a script printed to the log, one line at a time prefixed with
+/// the hook and script so output from scripts running at the same time can
+/// be told apart
+pub fn log_output(hook: Hook, script: &str, output: &Output) {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    for line in stdout.lines().chain(stderr.lines()) {
+        eprintln!("{} {}: {}", hook.name(), script, line);
+    }
+
+    if !output.status.success() {
+        eprintln!("{} {} exited with {}", hook.name(), script, output.status);
+    }
+}
+
+/// What a `pre_mount` hook decided should happen to a filesystem
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    /// Mount with the configured options
+    Mount,
+    /// Mount read-only, along with the configured options
+    ReadOnly,
+    /// Mount with these options instead of the configured ones
+    Options(String),
+    /// Leave the filesystem unmounted, for the given reason
+    Ignore(String)
+}
+
+impl Verdict {
+    /// Works out the verdict from how a `pre_mount` hook finished. A zero exit
+    /// status allows the mount and the first line of output can ask for
+    /// `read-only`, `options=<options>` or `ignore`. Anything else, including
+    /// the script failing to run, vetoes the mount with the output as the
+    /// reason
+    pub fn from_output(result: Result<Output, String>) -> Verdict {
+        let output = match result {
+            Ok(output) => output,
+            Err(e) => return Verdict::Ignore(format!("pre_mount hook failed: {}", e))
+        };
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let line = stdout.lines().next().unwrap_or("").trim();
+
+        if !output.status.success() {
+            return Verdict::Ignore(if line.is_empty() { "Vetoed by pre_mount hook".to_owned() } else { line.to_owned() });
+        }
+
+        match line {
+            "" | "mount" => Verdict::Mount,
+            "read-only" | "ro" => Verdict::ReadOnly,
+            "ignore" => Verdict::Ignore("Ignored by pre_mount hook".to_owned()),
+            _ if line.starts_with("options=") => match line["options=".len()..].trim() {
+                "" => Verdict::Ignore("pre_mount hook printed options= without any options".to_owned()),
+                options => Verdict::Options(options.to_owned())
+            },
+            _ => Verdict::Ignore(format!("pre_mount hook printed an unknown verdict '{}'", line))
+        }
+    }
+}
+
+/// Device details given to `pre_mount` hooks as JSON
+#[derive(Serialize)]
+pub struct DeviceInfo<'a> {
+    pub device: &'a str,
+    pub uuid: Option<&'a str>,
+    pub label: Option<&'a str>,
+    pub fstype: Option<&'a str>,
+    pub size: u64,
+    pub options: Option<&'a str>,
+    pub drive: Option<DriveInfo<'a>>
+}
+
+/// Details of the drive a device is on
+#[derive(Serialize)]
+pub struct DriveInfo<'a> {
+    pub vendor: Option<&'a str>,
+    pub model: Option<&'a str>,
+    pub serial: Option<&'a str>,
+    pub bus: Option<&'a str>,
+    pub removable: bool
+}
+
+impl<'a> DeviceInfo<'a> {
+    pub fn new(device: &'a Block, drive: Option<&'a Drive>, options: Option<&'a str>) -> Self {
+        DeviceInfo {
+            device: &device.device,
+            uuid: device.uuid.as_deref(),
+            label: device.label.as_deref(),
+            fstype: device.id_type.as_deref(),
+            size: device.size,
+            options,
+            drive: drive.map(|drive| DriveInfo {
+                vendor: drive.vendor.as_deref(),
+                model: drive.model.as_deref(),
+                serial: drive.serial.as_deref(),
+                bus: drive.connection_bus.as_deref(),
+                removable: drive.removable
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    fn output(code: i32, stdout: &str) -> Result<Output, String> {
+        // Wait statuses keep the exit code in the second byte
+        Ok(Output { status: ExitStatus::from_raw(code << 8), stdout: stdout.as_bytes().to_vec(), stderr: Vec::new() })
+    }
+
+    #[test]
+    fn zero_exit_status_mounts() {
+        assert_eq!(Verdict::from_output(output(0, "")), Verdict::Mount);
+        assert_eq!(Verdict::from_output(output(0, "mount\n")), Verdict::Mount);
+    }
+
+    #[test]
+    fn first_line_picks_the_verdict() {
+        assert_eq!(Verdict::from_output(output(0, "read-only\nmount\n")), Verdict::ReadOnly);
+        assert_eq!(Verdict::from_output(output(0, "ro")), Verdict::ReadOnly);
+        assert_eq!(Verdict::from_output(output(0, "options=noexec,nosuid\n")), Verdict::Options("noexec,nosuid".to_owned()));
+        assert_eq!(Verdict::from_output(output(0, "ignore\n")), Verdict::Ignore("Ignored by pre_mount hook".to_owned()));
+    }
+
+    #[test]
+    fn empty_options_are_rejected() {
+        assert!(matches!(Verdict::from_output(output(0, "options=\n")), Verdict::Ignore(_)));
+        assert!(matches!(Verdict::from_output(output(0, "options=  ")), Verdict::Ignore(_)));
+    }
+
+    #[test]
+    fn unknown_verdict_vetoes() {
+        assert!(matches!(Verdict::from_output(output(0, "maybe\n")), Verdict::Ignore(reason) if reason.contains("'maybe'")));
+    }
+
+    #[test]
+    fn failure_vetoes_with_the_output_as_reason() {
+        assert_eq!(Verdict::from_output(output(1, "Not on a Sunday\n")), Verdict::Ignore("Not on a Sunday".to_owned()));
+        assert_eq!(Verdict::from_output(output(1, "")), Verdict::Ignore("Vetoed by pre_mount hook".to_owned()));
+        assert!(matches!(Verdict::from_output(Err("timed out".to_owned())), Verdict::Ignore(reason) if reason.contains("timed out")));
+    }
+}
@@ -0,0 +1,62 @@
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A device that was removed while it was still in use
+pub struct UnsafeRemoval<'a> {
+    pub device: &'a str,
+    pub uuid: Option<&'a str>,
+    pub label: Option<&'a str>,
+    pub mount_path: Option<&'a str>,
+    pub unlocked: bool,
+    pub drive_model: Option<&'a str>,
+    pub drive_serial: Option<&'a str>
+}
+
+/// Location of the incident log, `$XDG_DATA_HOME/udman/incidents.log`
+pub fn log_path() -> Option<PathBuf> {
+    xdg::BaseDirectories::with_prefix("udman").ok()?
+        .place_data_file("incidents.log").ok()
+}
+
+/// Appends a line describing the removal to the incident log, along with
+/// the time and the user that was logged in
+pub fn record(removal: &UnsafeRemoval) -> std::io::Result<()> {
+    let path = log_path().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No data directory"))?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    writeln!(
+        file,
+        "{}\tuser={}\tdevice={}\tuuid={}\tlabel={}\tmount_path={}\tunlocked={}\tdrive_model={}\tdrive_serial={}",
+        utc_timestamp(),
+        std::env::var("USER").unwrap_or_default(),
+        removal.device,
+        removal.uuid.unwrap_or(""),
+        removal.label.unwrap_or(""),
+        removal.mount_path.unwrap_or(""),
+        removal.unlocked,
+        removal.drive_model.unwrap_or(""),
+        removal.drive_serial.unwrap_or("")
+    )
+}
+
+/// Current time formatted as an RFC 3339 UTC timestamp
+pub fn utc_timestamp() -> String {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, time) = (seconds / 86400, seconds % 86400);
+
+    // Convert days since the epoch into a calendar date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
@@ -0,0 +1,339 @@
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::config::{FsSettings, Keyfile};
+use crate::err::MounterError;
+use crate::keyring::Keyring;
+use crate::manager;
+use crate::prompts::{Answer, PasswordPrompter, PromptRequest};
+use crate::udisks2::devices::{Block, Drive, Encrypted, Filesystem};
+
+pub type JobId = u64;
+
+/// How long a `password_command` can run for before it's killed
+const PASSWORD_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Work that may block for a while and so is done away from the event loop
+pub enum Action {
+    Mount { filesystem: Filesystem, options: Option<String>, fstype: Option<String> },
+    /// Without a keyfile or password the passphrase comes from
+    /// `password_command` or else `keyring`, `remember` saves the password
+    /// in `keyring` if it works
+    Unlock {
+        encrypted: Encrypted,
+        keyfile: Option<String>,
+        password: Option<String>,
+        password_command: Option<String>,
+        keyring: Option<Keyring>,
+        remember: bool
+    },
+    /// Unlocks with the keyfile at `path` on `key_device`
+    UnlockWithKeyDevice { encrypted: Encrypted, key_device: Box<Filesystem>, path: String },
+    Prompt { prompter: Box<dyn PasswordPrompter>, request: PromptRequest },
+    Unmount { filesystem: Filesystem, lazy: bool },
+    Lock { encrypted: Encrypted },
+    /// Powers off a drive or ejects its media
+    Eject { drive: Drive },
+    Hook { script: String, env: Vec<(&'static str, String)>, input: Option<String>, timeout: Duration }
+}
+
+impl Action {
+    /// Unlocks with the keyfile, password or password command in `keys`
+    pub fn unlock(encrypted: Encrypted, keys: FsSettings, keyring: Option<Keyring>, remember: bool) -> Self {
+        Action::Unlock {
+            encrypted,
+            keyfile: keys.keyfile.as_ref().and_then(Keyfile::path).map(str::to_owned),
+            password: keys.password,
+            password_command: keys.password_command,
+            keyring,
+            remember
+        }
+    }
+}
+
+/// The result of an action
+pub enum Outcome {
+    Mounted(Result<String, dbus::Error>),
+    Unlocked(Result<String, MounterError>),
+    Prompted(Result<Option<Answer>, String>),
+    Unmounted(Result<(), MounterError>),
+    Locked(Result<(), dbus::Error>),
+    Ejected(Result<bool, dbus::Error>),
+    HookFinished(Result<Output, String>)
+}
+
+struct Job {
+    id: JobId,
+    action: Action,
+    cancelled: Arc<AtomicBool>
+}
+
+/// A finished job, jobs that were cancelled before finishing aren't reported
+pub struct Finished {
+    pub id: JobId,
+    pub outcome: Outcome
+}
+
+/// Handle to a job that has been queued
+#[derive(Debug)]
+pub struct Queued {
+    pub id: JobId,
+    pub deadline: Option<Instant>,
+    cancelled: Arc<AtomicBool>
+}
+
+impl Queued {
+    /// Stops the job from starting if it's still queued, running hooks are
+    /// killed and the results of anything else are thrown away
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_overdue(&self, now: Instant) -> bool {
+        self.deadline.map(|deadline| now >= deadline).unwrap_or(false)
+    }
+}
+
+/// A fixed number of worker threads that take actions off a shared queue
+#[derive(Debug)]
+pub struct Pool {
+    next_id: JobId,
+    queue: Sender<Job>,
+    finished: Receiver<Finished>
+}
+
+impl Pool {
+    pub fn new(workers: usize) -> Self {
+        let (queue, jobs) = mpsc::channel::<Job>();
+        let (finished_sender, finished) = mpsc::channel();
+        let jobs = Arc::new(Mutex::new(jobs));
+
+        for _ in 0..workers {
+            let jobs = Arc::clone(&jobs);
+            let finished_sender = finished_sender.clone();
+
+            thread::spawn(move || loop {
+                // Only hold the lock while waiting for a job so other workers
+                // can pick up jobs while this one is busy
+                let job = match jobs.lock().map(|jobs| jobs.recv()) {
+                    Ok(Ok(job)) => job,
+                    _ => return
+                };
+
+                if job.cancelled.load(Ordering::Relaxed) {
+                    continue;
+                }
+
+                let outcome = run(job.action, &job.cancelled);
+
+                if !job.cancelled.load(Ordering::Relaxed) && finished_sender.send(Finished { id: job.id, outcome }).is_err() {
+                    return;
+                }
+            });
+        }
+
+        Pool {
+            next_id: 0,
+            queue,
+            finished
+        }
+    }
+
+    /// Queues an action, it will be cancelled if it hasn't finished within
+    /// `timeout`
+    pub fn submit(&mut self, action: Action, timeout: Option<Duration>) -> Queued {
+        self.next_id += 1;
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let job = Job { id: self.next_id, action, cancelled: Arc::clone(&cancelled) };
+
+        // Workers only stop once the pool is dropped so this can't fail
+        self.queue.send(job).ok();
+
+        Queued {
+            id: self.next_id,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            cancelled
+        }
+    }
+
+    /// Jobs that have finished since this was last called
+    pub fn finished(&self) -> Vec<Finished> {
+        self.finished.try_iter().collect()
+    }
+}
+
+/// Carries out an action on this thread, for when there's no event loop
+/// waiting on the outcome
+pub fn run(action: Action, cancelled: &AtomicBool) -> Outcome {
+    match action {
+        Action::Mount { filesystem, options, fstype } => {
+            Outcome::Mounted(filesystem.mount(options.as_deref(), fstype.as_deref()))
+        },
+        Action::Unlock { encrypted, keyfile, password, password_command, keyring, remember } => {
+            let password = resolve_password(keyfile.as_deref(), password, password_command.as_deref(), &encrypted.device, cancelled);
+            let result = encrypted.unlock(keyfile, password.clone(), keyring.as_ref());
+
+            if let (Ok(_), true, Some(keyring), Some(password), Some(uuid)) = (&result, remember, &keyring, &password, &encrypted.device.uuid) {
+                let device = &encrypted.device;
+
+                if let Err(e) = keyring.store(uuid, device.label.as_deref().unwrap_or(&device.device), password) {
+                    eprintln!("Couldn't save the passphrase of {} in the keyring: {}", device.device, e.message().unwrap_or("D-Bus error"));
+                }
+            }
+
+            Outcome::Unlocked(result)
+        },
+        Action::UnlockWithKeyDevice { encrypted, key_device, path } => {
+            Outcome::Unlocked(unlock_with_key_device(&encrypted, &key_device, &path))
+        },
+        Action::Prompt { prompter, request } => {
+            Outcome::Prompted(prompter.prompt(&request, cancelled))
+        },
+        Action::Unmount { filesystem, lazy } => {
+            let device = &filesystem.device;
+
+            // Finding what's keeping a busy filesystem mounted reads through
+            // /proc so it's done here rather than in the event loop
+            Outcome::Unmounted(filesystem.unmount(lazy).map_err(|e| manager::unmount_error(manager::mount_path(device).unwrap_or(&device.device), e)))
+        },
+        Action::Lock { encrypted } => Outcome::Locked(encrypted.lock()),
+        Action::Eject { drive } => Outcome::Ejected(drive.eject()),
+        Action::Hook { script, env, input, timeout } => {
+            let mut command = Command::new(script);
+            command.envs(env);
+
+            Outcome::HookFinished(run_script(command, input, timeout, cancelled))
+        }
+    }
+}
+
+/// Unlocks a device with the keyfile at `path` on another filesystem. The
+/// filesystem is mounted read-only if it isn't mounted, and unmounted again
+/// once the key has been used
+pub fn unlock_with_key_device(encrypted: &Encrypted, key_device: &Filesystem, path: &str) -> Result<String, MounterError> {
+    let device = &key_device.device;
+    let (mount_path, mounted_here) = match manager::mount_path(device) {
+        Some(mount_path) => (mount_path.to_owned(), false),
+        None => {
+            let mount_path = key_device.mount(Some("ro"), None)
+                .map_err(|e| MounterError::MountFailed(device.device.to_owned(), e.message().unwrap_or("D-Bus error").to_owned()))?;
+
+            (mount_path, true)
+        }
+    };
+
+    let keyfile = Path::new(&mount_path).join(path);
+    let result = encrypted.unlock(Some(keyfile.to_string_lossy().into_owned()), None, None);
+
+    if mounted_here {
+        if let Err(e) = key_device.unmount(false) {
+            eprintln!("Couldn't unmount {} after reading the key: {}", device.device, e.message().unwrap_or("D-Bus error"));
+        }
+    }
+
+    result
+}
+
+/// The password to unlock a device with: `password` if there is one,
+/// otherwise what `password_command` prints. A keyfile takes precedence over
+/// both so the command isn't run if there is one
+pub fn resolve_password(keyfile: Option<&str>, password: Option<String>, password_command: Option<&str>, device: &Block, cancelled: &AtomicBool) -> Option<String> {
+    match (keyfile, password, password_command) {
+        (None, None, Some(password_command)) => password_from_command(password_command, device, cancelled),
+        (_, password, _) => password
+    }
+}
+
+/// Runs a `password_command` with the shell, its output without surrounding
+/// whitespace is the password. `None` if it fails or prints nothing. The
+/// output is the password so it's never logged, only what the command
+/// writes to stderr when it fails
+fn password_from_command(password_command: &str, device: &Block, cancelled: &AtomicBool) -> Option<String> {
+    let mut command = Command::new("/bin/sh");
+    command.arg("-c").arg(password_command).envs(vec!(
+        ("UDMAN_DEVICE", device.device.to_owned()),
+        ("UDMAN_UUID", device.uuid.to_owned().unwrap_or_default()),
+        ("UDMAN_LABEL", device.label.to_owned().unwrap_or_default())
+    ));
+
+    match run_script(command, None, PASSWORD_COMMAND_TIMEOUT, cancelled) {
+        Ok(output) if output.status.success() => {
+            Some(String::from_utf8_lossy(&output.stdout).trim().to_owned()).filter(|password| !password.is_empty())
+        },
+        Ok(output) => {
+            for line in String::from_utf8_lossy(&output.stderr).lines() {
+                eprintln!("password_command for {}: {}", device.device, line);
+            }
+
+            eprintln!("password_command for {} exited with {}", device.device, output.status);
+            None
+        },
+        Err(e) => {
+            eprintln!("password_command for {} failed: {}", device.device, e);
+            None
+        }
+    }
+}
+
+/// Runs a command, killing it if it takes longer than `timeout` or the job
+/// is cancelled. `input` is written to the command's stdin
+fn run_script(mut command: Command, input: Option<String>, timeout: Duration, cancelled: &AtomicBool) -> Result<Output, String> {
+    let mut child = command
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+
+    // Scripts that don't read their input would leave this blocked, so it's
+    // written from another thread that ends when the script exits
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        thread::spawn(move || stdin.write_all(input.as_bytes()).ok());
+    }
+
+    // Read output on separate threads so a chatty script can't fill the pipe
+    // and block while it's being waited on
+    let stdout = child.stdout.take().map(read_to_end);
+    let stderr = child.stderr.take().map(read_to_end);
+    let deadline = Instant::now() + timeout;
+
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            break status;
+        }
+
+        if cancelled.load(Ordering::Relaxed) {
+            child.kill().ok();
+            child.wait().ok();
+            return Err("cancelled".to_owned());
+        }
+
+        if Instant::now() >= deadline {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(format!("killed after {} seconds", timeout.as_secs()));
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.and_then(|reader| reader.join().ok()).unwrap_or_default(),
+        stderr: stderr.and_then(|reader| reader.join().ok()).unwrap_or_default()
+    })
+}
+
+fn read_to_end<R: Read + Send + 'static>(mut pipe: R) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        pipe.read_to_end(&mut output).ok();
+        output
+    })
+}
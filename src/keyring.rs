@@ -0,0 +1,355 @@
+use dbus::arg::{RefArg, Variant};
+use dbus::blocking::Proxy;
+use dbus::channel::{BusType, Channel};
+use dbus::message::MatchRule;
+use dbus::strings::Path;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::config::KeyringSettings;
+
+const SERVICE: &str = "org.freedesktop.secrets";
+const SERVICE_PATH: &str = "/org/freedesktop/secrets";
+const SERVICE_INTERFACE: &str = "org.freedesktop.Secret.Service";
+const COLLECTION_INTERFACE: &str = "org.freedesktop.Secret.Collection";
+const ITEM_INTERFACE: &str = "org.freedesktop.Secret.Item";
+const SESSION_INTERFACE: &str = "org.freedesktop.Secret.Session";
+const PROMPT_INTERFACE: &str = "org.freedesktop.Secret.Prompt";
+
+/// Where items are saved if the service doesn't say which collection is the
+/// default
+const DEFAULT_COLLECTION: &str = "/org/freedesktop/secrets/aliases/default";
+
+/// The schema GVfs and GNOME Disks save LUKS passphrases under, using it
+/// means passphrases saved by either of them are found and the other way
+/// round
+const SCHEMA: &str = "org.gnome.GVfs.Luks.Password";
+const UUID_ATTRIBUTE: &str = "gvfs-luks-uuid";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for the user to unlock the keyring
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A secret as the Secret Service sends it: the session, parameters, value
+/// and content type
+type Secret = (Path<'static>, Vec<u8>, Vec<u8>, String);
+
+/// Passphrases of encrypted devices saved in the freedesktop Secret Service,
+/// e.g. GNOME Keyring or KeePassXC, keyed by the UUID of the LUKS device
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    address: Option<String>
+}
+
+impl Keyring {
+    /// The keyring the settings point to, `None` if it's turned off
+    pub fn new(settings: &KeyringSettings) -> Option<Self> {
+        Some(Keyring { address: settings.address.to_owned() }).filter(|_| settings.enabled.unwrap_or(true))
+    }
+
+    /// The passphrase saved for a device, `None` if there isn't one or the
+    /// user wouldn't unlock the keyring
+    pub fn lookup(&self, uuid: &str) -> Result<Option<String>, dbus::Error> {
+        let conn = self.connect()?;
+        let service = proxy(&conn, SERVICE_PATH);
+        let (unlocked, locked): (Vec<Path<'static>>, Vec<Path<'static>>) = service.method_call(SERVICE_INTERFACE, "SearchItems", (attributes(uuid),))?;
+
+        let item = match (unlocked.into_iter().next(), locked.into_iter().next()) {
+            (Some(item), _) => item,
+            (None, Some(item)) if unlock(&conn, &item)? => item,
+            _ => return Ok(None)
+        };
+
+        let session = open_session(&service)?;
+        let secret: Result<(Secret,), dbus::Error> = proxy(&conn, item).method_call(ITEM_INTERFACE, "GetSecret", (session.clone(),));
+        close_session(&conn, session);
+
+        let ((_, _, value, _),) = secret?;
+
+        String::from_utf8(value)
+            .map(Some)
+            .map_err(|_| dbus::Error::new_custom("org.udman.Error.InvalidSecret", "The saved passphrase isn't valid UTF-8"))
+    }
+
+    /// Saves the passphrase of a device in the default collection, replacing
+    /// any passphrase that was saved for it before. `label` is the name of
+    /// the device shown in the keyring
+    pub fn store(&self, uuid: &str, label: &str, passphrase: &str) -> Result<(), dbus::Error> {
+        let conn = self.connect()?;
+        let service = proxy(&conn, SERVICE_PATH);
+
+        let collection = service.method_call(SERVICE_INTERFACE, "ReadAlias", ("default",))
+            .map(|(collection,): (Path<'static>,)| collection)
+            .ok()
+            .filter(|collection| &**collection != "/")
+            .unwrap_or_else(|| Path::from(DEFAULT_COLLECTION));
+
+        if !unlock(&conn, &collection)? {
+            return Err(dbus::Error::new_custom("org.freedesktop.Secret.Error.IsLocked", "The keyring wasn't unlocked"));
+        }
+
+        let mut properties: HashMap<&str, Variant<Box<dyn RefArg>>> = HashMap::new();
+        properties.insert("org.freedesktop.Secret.Item.Label", Variant(Box::new(format!("Encryption passphrase for {}", label))));
+        properties.insert("org.freedesktop.Secret.Item.Attributes", Variant(Box::new(attributes(uuid))));
+
+        let session = open_session(&service)?;
+        let secret: Secret = (session.clone(), Vec::new(), passphrase.as_bytes().to_vec(), "text/plain; charset=utf8".to_owned());
+        let created: Result<(Path<'static>, Path<'static>), dbus::Error> = proxy(&conn, collection)
+            .method_call(COLLECTION_INTERFACE, "CreateItem", (properties, secret, true));
+        close_session(&conn, session);
+
+        let (_, prompt) = created?;
+
+        if prompt_user(&conn, &prompt)? {
+            Ok(())
+        } else {
+            Err(dbus::Error::new_custom("org.freedesktop.Secret.Error.IsLocked", "Saving the passphrase was dismissed"))
+        }
+    }
+
+    /// Connects to the bus at the configured address, or the session bus
+    fn connect(&self) -> Result<Channel, dbus::Error> {
+        match &self.address {
+            Some(address) => {
+                let mut channel = Channel::open_private(address)?;
+                channel.register()?;
+
+                Ok(channel)
+            },
+            None => Channel::get_private(BusType::Session)
+        }
+    }
+}
+
+fn proxy<'a>(conn: &'a Channel, path: impl Into<Path<'a>>) -> Proxy<'a, &'a Channel> {
+    Proxy::new(SERVICE, path, TIMEOUT, conn)
+}
+
+fn attributes(uuid: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    attributes.insert("xdg:schema".to_owned(), SCHEMA.to_owned());
+    attributes.insert(UUID_ATTRIBUTE.to_owned(), uuid.to_owned());
+
+    attributes
+}
+
+/// Opens a session that sends secrets as they are. They only travel over
+/// the bus between processes of the same user, like the passphrase does on
+/// its way to UDisks2
+fn open_session(service: &Proxy<'_, &Channel>) -> Result<Path<'static>, dbus::Error> {
+    let (_, session): (Variant<Box<dyn RefArg>>, Path<'static>) = service.method_call(SERVICE_INTERFACE, "OpenSession", ("plain", Variant("")))?;
+
+    Ok(session)
+}
+
+fn close_session(conn: &Channel, session: Path<'static>) {
+    proxy(conn, session).method_call::<(), _, _, _>(SESSION_INTERFACE, "Close", ()).ok();
+}
+
+/// Unlocks an item or collection, asking the user to unlock the keyring if
+/// the service wants to. Returns false if they wouldn't
+fn unlock(conn: &Channel, object: &Path<'static>) -> Result<bool, dbus::Error> {
+    let (_, prompt): (Vec<Path<'static>>, Path<'static>) = proxy(conn, SERVICE_PATH)
+        .method_call(SERVICE_INTERFACE, "Unlock", (vec![object.clone()],))?;
+
+    prompt_user(conn, &prompt)
+}
+
+/// Shows a prompt the service has asked for and waits for it to complete,
+/// returning false if it was dismissed. `/` means there's nothing to ask
+fn prompt_user(conn: &Channel, prompt: &Path<'static>) -> Result<bool, dbus::Error> {
+    if &**prompt == "/" {
+        return Ok(true);
+    }
+
+    let mut rule = MatchRule::new_signal(PROMPT_INTERFACE, "Completed");
+    rule.path = Some(prompt.clone());
+
+    Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", TIMEOUT, conn)
+        .method_call::<(), _, _, _>("org.freedesktop.DBus", "AddMatch", (rule.match_str(),))?;
+
+    // The window the prompt belongs to, there isn't one
+    let prompt = proxy(conn, prompt.clone());
+    prompt.method_call::<(), _, _, _>(PROMPT_INTERFACE, "Prompt", ("",))?;
+
+    let deadline = Instant::now() + PROMPT_TIMEOUT;
+
+    while Instant::now() < deadline {
+        if let Some(message) = conn.blocking_pop_message(Duration::from_millis(500))? {
+            if rule.matches(&message) {
+                return Ok(!message.read1::<bool>().unwrap_or(true));
+            }
+        }
+    }
+
+    prompt.method_call::<(), _, _, _>(PROMPT_INTERFACE, "Dismiss", ()).ok();
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::Message;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    const COLLECTION: &str = "/org/freedesktop/secrets/collection/login";
+    const SESSION: &str = "/org/freedesktop/secrets/session/1";
+
+    type Properties = HashMap<String, Variant<Box<dyn RefArg>>>;
+
+    /// A bus of the test's own, so the user's keyring is never touched. It's
+    /// stopped when it's dropped
+    struct Bus {
+        daemon: Child,
+        address: String
+    }
+
+    impl Bus {
+        fn start() -> Bus {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("dbus-daemon is needed to run the keyring tests");
+
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+
+            Bus { daemon, address: address.trim().to_owned() }
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            self.daemon.kill().ok();
+            self.daemon.wait().ok();
+        }
+    }
+
+    /// An item saved in the stand-in's only collection
+    #[derive(Clone, Debug)]
+    struct Item {
+        label: String,
+        attributes: HashMap<String, String>,
+        secret: Vec<u8>
+    }
+
+    /// Answers the Secret Service calls `Keyring` makes on `bus`, keeping
+    /// items in memory. Nothing is locked so there's never a prompt
+    fn secret_service(bus: &Bus) -> Arc<Mutex<Vec<Item>>> {
+        let items = Arc::new(Mutex::new(Vec::new()));
+        let mut channel = Channel::open_private(&bus.address).unwrap();
+        channel.register().unwrap();
+
+        Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", TIMEOUT, &channel)
+            .method_call::<(u32,), _, _, _>("org.freedesktop.DBus", "RequestName", (SERVICE, 4u32))
+            .unwrap();
+
+        let service_items = Arc::clone(&items);
+        thread::spawn(move || {
+            // Ends once the bus is stopped
+            while let Ok(message) = channel.blocking_pop_message(Duration::from_millis(100)) {
+                if let Some(reply) = message.and_then(|message| answer(&message, &service_items)) {
+                    channel.send(reply).ok();
+                }
+            }
+        });
+
+        items
+    }
+
+    fn answer(call: &Message, items: &Mutex<Vec<Item>>) -> Option<Message> {
+        let path = call.path()?.to_string();
+        let mut items = items.lock().unwrap();
+        let item_path = |index: usize| Path::from(format!("{}/{}", COLLECTION, index));
+
+        let reply = match &*call.member()? {
+            "OpenSession" => call.method_return().append2(Variant(""), Path::from(SESSION)),
+            "Close" => call.method_return(),
+            "ReadAlias" => call.method_return().append1(Path::from(COLLECTION)),
+            "Unlock" => {
+                let objects: Vec<Path<'static>> = call.read1().ok()?;
+                call.method_return().append2(objects, Path::from("/"))
+            },
+            "SearchItems" => {
+                let attributes: HashMap<String, String> = call.read1().ok()?;
+                let found: Vec<Path<'static>> = (0..items.len())
+                    .filter(|&index| attributes.iter().all(|(key, value)| items[index].attributes.get(key) == Some(value)))
+                    .map(item_path)
+                    .collect();
+
+                call.method_return().append2(found, Vec::<Path<'static>>::new())
+            },
+            "CreateItem" => {
+                let (properties, (_, _, secret, _), _): (Properties, Secret, bool) = call.read3().ok()?;
+                let label = properties.get("org.freedesktop.Secret.Item.Label")?.0.as_str()?.to_owned();
+                let mut pairs = properties.get("org.freedesktop.Secret.Item.Attributes")?.0.as_iter()?;
+                let mut attributes = HashMap::new();
+
+                while let (Some(key), Some(value)) = (pairs.next(), pairs.next()) {
+                    attributes.insert(key.as_str()?.to_owned(), value.as_str()?.to_owned());
+                }
+
+                items.push(Item { label, attributes, secret });
+                call.method_return().append2(item_path(items.len() - 1), Path::from("/"))
+            },
+            "GetSecret" => {
+                let index: usize = path.rsplit('/').next()?.parse().ok()?;
+                let secret: Secret = (Path::from(SESSION), Vec::new(), items.get(index)?.secret.to_owned(), "text/plain".to_owned());
+
+                call.method_return().append1(secret)
+            },
+            _ => return None
+        };
+
+        Some(reply)
+    }
+
+    fn keyring(bus: &Bus) -> Keyring {
+        Keyring::new(&KeyringSettings { enabled: None, address: Some(bus.address.to_owned()) }).unwrap()
+    }
+
+    #[test]
+    fn stored_passphrase_is_looked_up() {
+        let bus = Bus::start();
+        let items = secret_service(&bus);
+        let keyring = keyring(&bus);
+
+        keyring.store("1234-ABCD", "BACKUP", "correct horse").unwrap();
+
+        assert_eq!(keyring.lookup("1234-ABCD").unwrap().as_deref(), Some("correct horse"));
+        assert_eq!(items.lock().unwrap()[0].label, "Encryption passphrase for BACKUP");
+    }
+
+    #[test]
+    fn passphrase_is_saved_where_gnome_disks_finds_it() {
+        let bus = Bus::start();
+        let items = secret_service(&bus);
+
+        keyring(&bus).store("1234-ABCD", "BACKUP", "correct horse").unwrap();
+
+        let attributes = &items.lock().unwrap()[0].attributes;
+        assert_eq!(attributes.get("xdg:schema").map(String::as_str), Some("org.gnome.GVfs.Luks.Password"));
+        assert_eq!(attributes.get("gvfs-luks-uuid").map(String::as_str), Some("1234-ABCD"));
+    }
+
+    #[test]
+    fn unknown_device_has_no_passphrase() {
+        let bus = Bus::start();
+        let _items = secret_service(&bus);
+        let keyring = keyring(&bus);
+
+        keyring.store("1234-ABCD", "BACKUP", "correct horse").unwrap();
+
+        assert_eq!(keyring.lookup("5678-EF01").unwrap(), None);
+    }
+
+    #[test]
+    fn keyring_can_be_turned_off() {
+        assert!(Keyring::new(&KeyringSettings { enabled: Some(false), address: None }).is_none());
+    }
+}
@@ -8,14 +8,37 @@ mod config;
 mod notices;
 mod notifications;
 mod manager;
+mod incidents;
+mod processes;
+mod jobs;
+mod keyring;
+mod prompts;
+mod hooks;
+mod watcher;
+mod events;
+mod service;
+mod client;
+mod watch;
 pub use manager::Manager;
-pub use config::{Config, Settings, FsSettings};
+pub use config::{Config, Settings, FsSettings, Keyfile, KeyOnDevice, FstypeSettings, Hooks, NotificationSettings, NoticeSettings, SinkSettings, PromptSettings, EventSettings, KeyringSettings, Rule, RuleMatch};
+pub use err::ConfigError;
+pub use client::Client;
+pub use watch::{watch, WatchFilter};
+pub use service::DeviceStatus;
+pub use notices::format_size;
 use udisks2::devices::{Block, Drive};
+use notifications::NotificationEvent;
+use watcher::ConfigWatcher;
+use service::Service;
+use std::path::Path;
 
-/// Sets up a listener and starts listening for device events
-pub fn run(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+/// Sets up a listener and starts listening for device events. The
+/// configuration is reloaded whenever the file at `config_path` changes or
+/// a SIGHUP is received. Other programs can control udman through
+/// `org.udman.Manager1` on the session bus, if there is one
+pub fn run(config: Config, config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
     let mut udisks2_listener = udisks2::Listener::new();
-    let manager = std::rc::Rc::new(std::cell::RefCell::new(Manager::new(config, udisks2::current_state().ok())));
+    let manager = std::rc::Rc::new(std::cell::RefCell::new(Manager::new(config, config_path, udisks2::current_state().ok())));
 
     let manager_clone = manager.clone();
     udisks2_listener.drive_added(move |drive: Drive| {
@@ -35,6 +58,48 @@ pub fn run(config: Config) -> Result<(), Box<dyn std::error::Error>> {
         manager.removed_object(object_path);
     });
 
+    let manager_clone = manager.clone();
+    udisks2_listener.properties_changed(move |object_path: String, interface: String, properties| {
+        let mut manager = manager_clone.borrow_mut();
+        manager.changed_properties(object_path, interface, properties);
+    });
+
+    let service = match Service::start(manager.clone()) {
+        Ok(service) => Some(service),
+        Err(e) => {
+            eprintln!("Could not start D-Bus service: {}", e.message().unwrap_or("D-Bus error"));
+            None
+        }
+    };
+
+    let service = std::cell::RefCell::new(service);
+    let manager_clone = manager.clone();
+    let config_watcher = std::cell::RefCell::new(ConfigWatcher::new(config_path));
+    udisks2_listener.tick(move || {
+        manager_clone.borrow_mut().process_jobs();
+
+        for event in notifications::events() {
+            match event {
+                NotificationEvent::ActionInvoked(action) => manager_clone.borrow_mut().notification_action(&action),
+                NotificationEvent::Closed(id) => manager_clone.borrow_mut().notification_closed(id)
+            }
+        }
+
+        if let Some(service) = service.borrow_mut().as_mut() {
+            service.process();
+        }
+
+        let mut config_watcher = config_watcher.borrow_mut();
+        let (hangup, file_changed) = (config_watcher.hangup(), config_watcher.file_changed());
+
+        if hangup {
+            // Failures are reported by reload itself
+            manager_clone.borrow_mut().reload().ok();
+        } else if file_changed {
+            manager_clone.borrow_mut().config_file_changed();
+        }
+    });
+
     udisks2_listener.run()
 }
 
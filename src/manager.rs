@@ -1,27 +1,256 @@
-use super::udisks2::{Udisks2ManagedObjects, devices::{Block, Drive, Encrypted, Filesystem}};
+use super::udisks2::{Udisks2ManagedObjects, Udisks2Properties, devices::{Block, Drive, Encrypted, Filesystem}};
 use std::collections::HashMap;
 use super::notifications::{Notifier};
-use super::notices::Notice;
-use super::config::Config;
-use dialog::DialogBox;
+use super::notices::{Details, Notice};
+use super::config::{Config, FsSettings, KeyOnDevice, Keyfile, PromptSettings};
+use super::incidents;
+use super::err::{ConfigError, MounterError};
+use super::processes;
+use super::jobs::{Action, JobId, Outcome, Pool, Queued};
+use super::keyring::Keyring;
+use super::prompts::{self, PromptRequest};
+use super::hooks::{self, DeviceInfo, Hook, Verdict};
+use super::events::{Event, Publisher};
+use std::sync::mpsc::Receiver;
+use super::config::Hooks;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of jobs that can run at the same time, such as mounts and prompts
+const WORKERS: usize = 4;
+/// How long to wait for UDisks2 to mount or unlock a device
+const DBUS_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long to wait for the user to enter a password
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(300);
+/// How long to wait for the device holding a keyfile to be attached
+const KEY_DEVICE_TIMEOUT: Duration = Duration::from_secs(300);
 
 /// Keeps track of and controls devices and drives
 #[derive(Debug)]
 pub struct Manager {
     config: Config,
+    config_path: PathBuf,
     drives: HashMap<String, Drive>,
-    devices: HashMap<String, Block>
+    devices: HashMap<String, Block>,
+    // The drive each device is on, kept so it can still be reported if
+    // UDisks2 removes the drive before its devices
+    device_drives: HashMap<String, Drive>,
+    // Encrypted devices udman is unlocking, used to report the unlock and the
+    // mount of the cleartext device together
+    unlocking: HashSet<String>,
+    // Encrypted devices waiting for the device holding their keyfile
+    waiting_for_key: Vec<WaitingForKey>,
+    // Whether each filesystem was to be mounted automatically when its
+    // settings were last looked at, a reload only mounts the ones it has
+    // just been turned on for
+    automount: HashMap<String, bool>,
+    // Filesystems the user ignored or unmounted, or a `pre_mount` hook
+    // vetoed, which reloads leave alone
+    dismissed: HashSet<String>,
+    // What udman last wrote to the config file, so the change it makes
+    // isn't reloaded
+    written_config: Option<String>,
+    // Drives and devices reported as removed while in use, a drive's devices
+    // go away one after another and it's only reported once
+    removed_in_use: HashSet<String>,
+    pool: Pool,
+    jobs: HashMap<JobId, (Queued, Pending)>,
+    notifier: Notifier,
+    publisher: Publisher
+}
+
+/// What a queued job was for, used to act on the outcome
+#[derive(Debug)]
+enum Pending {
+    PreMount { filesystem: Filesystem, unlocked: bool, options: Option<String>, fstype: Option<String>, script: String },
+    /// `reply` is set when someone asked for the mount and is waiting
+    Mount { device: Block, unlocked: bool, reply: Option<Reply<String>> },
+    /// `attempt` is how many times the user has been asked for the
+    /// passphrase, it's set when they can be asked if the unlock fails. 0
+    /// means the passphrase was looked up in the keyring. `key_device` is
+    /// the filesystem the keyfile is read from, if it's on another device
+    Unlock { device: Block, attempt: Option<u32>, key_device: Option<String>, reply: Option<Reply<String>> },
+    Prompt { encrypted: Encrypted, attempt: u32 },
+    Hook { device: Block, hook: Hook, script: String },
+    /// `lock` is the encrypted device to lock once the filesystem inside it
+    /// is unmounted
+    Unmount { device: Block, lock: Option<Encrypted>, reply: Option<Reply<()>> },
+    Lock { device: Block, reply: Option<Reply<()>> },
+    Eject { ejecting: Ejecting, step: EjectStep }
+}
+
+impl Pending {
+    /// The device the job is for, ejects are for a whole drive
+    fn device(&self) -> Option<&Block> {
+        match self {
+            Self::Mount { device, .. } | Self::Unlock { device, .. } | Self::Hook { device, .. } => Some(device),
+            Self::Unmount { device, .. } | Self::Lock { device, .. } => Some(device),
+            Self::Prompt { encrypted, .. } => Some(&encrypted.device),
+            Self::PreMount { filesystem, .. } => Some(&filesystem.device),
+            Self::Eject { .. } => None
+        }
+    }
+
+    /// Whether the job is for the device, or for the drive it's on
+    fn is_for(&self, object_path: &str) -> bool {
+        match self {
+            Self::Eject { ejecting, .. } => ejecting.devices.iter().any(|device| &*device.object_path == object_path),
+            _ => self.device().map(|device| &*device.object_path) == Some(object_path)
+        }
+    }
+
+    fn key_device(&self) -> Option<&str> {
+        match self {
+            Self::Unlock { key_device, .. } => key_device.as_deref(),
+            _ => None
+        }
+    }
+
+    /// The device other jobs can use once this one is done, the one holding
+    /// the keyfile if there is one
+    fn freed_device(&self) -> Option<String> {
+        self.key_device().or_else(|| Some(&*self.device()?.object_path)).map(str::to_owned)
+    }
+}
+
+/// An encrypted device whose keyfile is on a device that isn't attached or
+/// that udman is busy with
+#[derive(Debug)]
+struct WaitingForKey {
+    encrypted: Encrypted,
+    key: KeyOnDevice,
+    deadline: Instant
+}
+
+/// A drive being ejected. The steps are carried out one at a time so each
+/// one can be reported as it's done
+#[derive(Debug)]
+struct Ejecting {
+    drive: Drive,
+    // Everything on the drive, which udman leaves alone until it's done
+    devices: Vec<Block>,
+    steps: VecDeque<EjectStep>,
+    lazy: bool,
+    reply: Option<Reply<()>>
+}
+
+/// Something that has to be done before a drive can be removed, see
+/// `eject_steps`
+#[derive(Debug)]
+pub(crate) enum EjectStep {
+    Unmount(Filesystem),
+    Lock(Encrypted),
+    PowerOff
+}
+
+impl EjectStep {
+    /// The job that carries out the step, `lazy` unmounts filesystems even
+    /// if they're busy
+    pub(crate) fn action(&self, drive: &Drive, lazy: bool) -> Action {
+        match self {
+            Self::Unmount(filesystem) => Action::Unmount { filesystem: filesystem.to_owned(), lazy },
+            Self::Lock(encrypted) => Action::Lock { encrypted: encrypted.to_owned() },
+            Self::PowerOff => Action::Eject { drive: drive.to_owned() }
+        }
+    }
+
+    /// Whether the step's job worked, the error names what's blocking the
+    /// eject if it didn't
+    pub(crate) fn result(&self, drive: &Drive, outcome: Outcome) -> Result<(), MounterError> {
+        match (self, outcome) {
+            (Self::Unmount(_), Outcome::Unmounted(result)) => result,
+            (Self::Lock(encrypted), Outcome::Locked(result)) => {
+                result.map_err(|e| MounterError::LockFailed(encrypted.device.device.to_owned(), e))
+            },
+            (Self::PowerOff, Outcome::Ejected(Ok(true))) => Ok(()),
+            (Self::PowerOff, Outcome::Ejected(Ok(false))) => Err(MounterError::NotPoweredOff(drive.name())),
+            (Self::PowerOff, Outcome::Ejected(Err(e))) => Err(MounterError::EjectFailed(drive.name(), e)),
+            _ => unreachable!()
+        }
+    }
+}
+
+/// How an encrypted device is unlocked, see `unlock_with`
+pub(crate) enum UnlockWith {
+    /// It's unlocked already, this is the cleartext device
+    Unlocked(String),
+    /// The keyfile, password or password command in `keys`, without any of
+    /// them the keyring is tried. `remember` saves the password in the
+    /// keyring if it works
+    Keys { encrypted: Encrypted, keys: FsSettings, remember: bool },
+    /// The keyfile at `path` on another filesystem
+    KeyDevice { encrypted: Encrypted, key_device: Filesystem, path: String }
+}
+
+/// Someone waiting for a request to be carried out, such as a D-Bus method
+/// call. It's called once with the result, if the request is dropped before
+/// finishing, e.g. because the device was removed, it's told it was cancelled
+pub struct Reply<T>(Option<ReplyFn<T>>);
+
+type ReplyFn<T> = Box<dyn FnOnce(Result<T, MounterError>)>;
+
+impl<T> Reply<T> {
+    pub fn new<F: FnOnce(Result<T, MounterError>) + 'static>(f: F) -> Self {
+        Reply(Some(Box::new(f)))
+    }
+
+    pub fn send(mut self, result: Result<T, MounterError>) {
+        if let Some(f) = self.0.take() {
+            f(result);
+        }
+    }
+}
+
+impl<T> Drop for Reply<T> {
+    fn drop(&mut self) {
+        if let Some(f) = self.0.take() {
+            f(Err(MounterError::Cancelled));
+        }
+    }
+}
+
+impl<T> fmt::Debug for Reply<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Reply")
+    }
+}
+
+/// Sends the result to whoever asked for it, if anyone did
+fn send_reply<T>(reply: Option<Reply<T>>, result: Result<T, MounterError>) {
+    if let Some(reply) = reply {
+        reply.send(result);
+    }
 }
 
 impl Manager {
-    /// Create a new instance of manager with the specified configuration.
+    /// Create a new instance of manager with the specified configuration,
+    /// choices the user makes for a device are saved to `config_path`.
     /// Initial UDisks2 state can be passed in also this allows udman to keep
     /// track of devices that were attached before the program started running.
-    pub fn new(config: Config, initial_state: Option<Udisks2ManagedObjects>) -> Manager {
+    pub fn new(config: Config, config_path: &Path, initial_state: Option<Udisks2ManagedObjects>) -> Manager {
+        let notifier = Notifier::new(&config.notifications.sink);
+        let publisher = Publisher::new(&config.events);
         let mut new_manager = Manager {
             config: config,
+            config_path: config_path.to_owned(),
             drives: HashMap::new(),
-            devices: HashMap::new()
+            devices: HashMap::new(),
+            device_drives: HashMap::new(),
+            unlocking: HashSet::new(),
+            waiting_for_key: Vec::new(),
+            automount: HashMap::new(),
+            dismissed: HashSet::new(),
+            written_config: None,
+            removed_in_use: HashSet::new(),
+            pool: Pool::new(WORKERS),
+            jobs: HashMap::new(),
+            notifier,
+            publisher
         };
 
         if let Some(initial_state) = initial_state {
@@ -49,6 +278,12 @@ impl Manager {
             self.new_drive(drive);
         }
 
+        // Every device is known before any is handled, so a keyfile on a
+        // device that's already attached is found whichever comes first
+        for device in &devices {
+            self.devices.insert(device.object_path.to_string(), device.to_owned());
+        }
+
         for device in devices {
             self.new_device(device);
         }
@@ -57,14 +292,59 @@ impl Manager {
     /// Inserts a new drive object into the list of monitored drives
     pub fn new_drive(&mut self, drive: Drive) {
         self.drives.insert(drive.object_path.to_string(), drive.to_owned());
+
+        // Devices can be added before the drive they're on
+        let on_drive: Vec<String> = self.devices.values()
+            .filter(|device| self.drive_for(device).map(|on| on.object_path == drive.object_path).unwrap_or(false))
+            .map(|device| device.object_path.to_string())
+            .collect();
+
+        for object_path in on_drive {
+            self.device_drives.insert(object_path, drive.to_owned());
+        }
     }
 
     /// Inserts a new block device object into the list of monitored drives
     pub fn new_device(&mut self, device: Block) {
         self.devices.insert(device.object_path.to_string(), device.to_owned());
 
-        if let Some(filesystem) = device.as_fs() {
-            self.new_filesystem(filesystem);
+        if let Some(drive) = self.drive_for(&device).cloned() {
+            self.device_drives.insert(device.object_path.to_string(), drive);
+        }
+
+        // The drive is back so it can be reported again
+        self.removed_in_use.remove(&*device.object_path);
+
+        if let Some(drive) = &device.drive {
+            self.removed_in_use.remove(drive);
+        }
+
+        if device.fs_info.is_some() {
+            self.automount.insert(device.object_path.to_string(), self.automount(&device));
+        }
+
+        // Cleartext devices are covered by the unlock hook
+        let attached = device.crypto_backing_device.is_none() && (device.fs_info.is_some() || device.enc_info.is_some());
+
+        if attached && self.is_removable(&device) {
+            self.run_hook(Hook::Attach, &device, mount_path(&device), None);
+        }
+
+        let unlocked_by_udman = device.crypto_backing_device.as_ref()
+            .map(|backing_device| self.unlocking.remove(backing_device))
+            .unwrap_or(false);
+
+        if unlocked_by_udman {
+            match device.as_fs() {
+                Some(filesystem) => self.unlocked_filesystem(filesystem),
+                None => self.notify(&device, Notice::DecryptSuccess(&device.device), &[])
+            }
+        } else if let Some(filesystem) = device.as_fs() {
+            // A device holding keyfiles that are needed is only mounted to
+            // read them
+            if !self.key_device_attached(&filesystem.device) && !self.has_job(&filesystem.device.object_path) {
+                self.new_filesystem(filesystem);
+            }
         }
 
         if let Some(encrypted) = device.as_enc() {
@@ -72,95 +352,1341 @@ impl Manager {
         }
     }
 
+    /// Swaps in a new configuration and re-evaluates devices that are already
+    /// attached, so that newly enabled settings take effect immediately.
+    /// Filesystems are only mounted if `automount` has just been turned on
+    /// for them, the user hasn't ignored or unmounted them and they don't
+    /// hold keyfiles. Locked devices are only unlocked if the new
+    /// configuration provides a key for them, the user isn't prompted again.
+    pub fn reload_config(&mut self, config: Config) {
+        self.notifier.configure(&config.notifications.sink);
+        self.publisher.configure(&config.events);
+        self.config = config;
+
+        let devices: Vec<Block> = self.devices.values().cloned().collect();
+
+        for device in devices {
+            // Devices with a job in flight will be dealt with when it finishes
+            if self.has_job(&device.object_path) {
+                continue;
+            }
+
+            if let Some(filesystem) = device.as_fs() {
+                let object_path = filesystem.device.object_path.to_string();
+                let automount = self.automount(&filesystem.device);
+                let turned_on = self.automount.insert(object_path.to_owned(), automount) == Some(false) && automount;
+
+                // Devices holding keyfiles are left for the unlocks that need them
+                let holds_keyfiles = matches!(&filesystem.device.uuid, Some(uuid) if self.config.holds_keyfiles(uuid));
+
+                if turned_on && !holds_keyfiles && !self.dismissed.contains(&object_path) && self.is_removable(&filesystem.device) && !is_mounted(&filesystem.device) {
+                    self.apply_fs_settings(filesystem, false, &[]);
+                }
+            }
+
+            if let Some(encrypted) = device.as_enc() {
+                let settings = self.settings_for(&encrypted.device);
+                if settings.has_key() && !is_unlocked(&encrypted.device) {
+                    self.unlock(encrypted, false);
+                }
+            }
+        }
+    }
+
     fn new_encrypted(&mut self, encrypted: Encrypted) {
         // Don't unlock if the device is already decrypted
-        if let Some(enc_info) = &encrypted.device.enc_info {
-            if enc_info.cleartext_device.is_some() {
-                return;
+        if is_unlocked(&encrypted.device) {
+            return;
+        }
+
+        self.unlock(encrypted, true);
+    }
+
+    /// Unlocks the device with the configured key, if there isn't one then
+    /// the user is asked for a password when `prompt` is set
+    fn unlock(&mut self, encrypted: Encrypted, prompt: bool) {
+        let settings = self.settings_for(&encrypted.device);
+
+        if let Some(Keyfile::OnDevice(key)) = settings.keyfile {
+            self.publish_decision(&encrypted.device, "unlock");
+            let deadline = Instant::now() + key.timeout.map(Duration::from_secs).unwrap_or(KEY_DEVICE_TIMEOUT);
+            self.unlock_with_key_device(encrypted, key, deadline);
+        } else if settings.has_key() {
+            self.publish_decision(&encrypted.device, "unlock");
+            self.queue_unlock(encrypted, settings, None, false, None);
+        } else if prompt && self.keyring().is_some() && encrypted.device.uuid.is_some() {
+            // The user is only asked if there's no passphrase saved for the
+            // device or it no longer works
+            self.publish_decision(&encrypted.device, "keyring");
+            self.queue_unlock(encrypted, FsSettings::default(), Some(0), false, None);
+        } else if prompt {
+            let device = encrypted.device.to_owned();
+
+            self.publish_decision(&device, "prompt");
+            self.notify(&device, Notice::NewEncrypted(&device.device), &[]);
+            self.prompt(encrypted, 1, None);
+        } else {
+            self.notify(&encrypted.device.to_owned(), Notice::DecryptFail(&MounterError::NoKeyProvided.to_string()), &[]);
+        }
+    }
+
+    /// Asks the user for the passphrase of a device using the prompt its
+    /// settings choose. `error` is why the last attempt failed
+    fn prompt(&mut self, encrypted: Encrypted, attempt: u32, error: Option<String>) {
+        let device = &encrypted.device;
+        let prompt = self.settings_for(device).prompt
+            .or_else(|| self.config.settings.prompt.clone())
+            .unwrap_or(PromptSettings::Dialog);
+
+        let request = PromptRequest {
+            label: device_name(device).to_owned(),
+            device: device.device.to_owned(),
+            size: Some(device.size).filter(|size| *size > 0),
+            drive: self.drive_for(device).map(Drive::name),
+            attempt,
+            attempts: self.unlock_attempts(),
+            error,
+            remember: self.keyring().is_some() && device.uuid.is_some()
+        };
+
+        let action = Action::Prompt { prompter: prompts::from_settings(&prompt), request };
+
+        self.queue(action, Some(PROMPT_TIMEOUT), Pending::Prompt { encrypted, attempt });
+    }
+
+    fn unlock_attempts(&self) -> u32 {
+        self.config.settings.unlock_attempts.unwrap_or(prompts::DEFAULT_ATTEMPTS)
+    }
+
+    fn keyring(&self) -> Option<Keyring> {
+        Keyring::new(&self.config.keyring)
+    }
+
+    /// Unlocks a device in the background with the keyfile, password or
+    /// password command in `keys`, without any of them the keyring is tried.
+    /// `attempt` is set when the user can be asked for the passphrase if it
+    /// fails, `remember` saves the password in the keyring if it works.
+    /// `reply` is told the cleartext device once it's unlocked
+    fn queue_unlock(&mut self, encrypted: Encrypted, keys: FsSettings, attempt: Option<u32>, remember: bool, reply: Option<Reply<String>>) {
+        let device = encrypted.device.to_owned();
+        let action = Action::unlock(encrypted, keys, self.keyring(), remember);
+
+        self.notify(&device, Notice::Unlocking(&device.device), &[]);
+        self.unlocking.insert(device.object_path.to_string());
+        self.queue(action, Some(DBUS_TIMEOUT), Pending::Unlock { device, attempt, key_device: None, reply });
+    }
+
+    /// Unlocks a device with a keyfile on another device. If that device
+    /// isn't attached, or udman is busy with it e.g. reading a keyfile for
+    /// another device, this waits for it until `deadline`
+    fn unlock_with_key_device(&mut self, encrypted: Encrypted, key: KeyOnDevice, deadline: Instant) {
+        // Asking again, e.g. after the config is reloaded, starts the wait over
+        self.waiting_for_key.retain(|waiting| waiting.encrypted.device.object_path != encrypted.device.object_path);
+
+        let key_device = self.key_device(&key.uuid)
+            .filter(|key_device| !self.has_job(&key_device.device.object_path));
+
+        match key_device {
+            Some(key_device) => self.queue_unlock_with_key_device(encrypted, key_device, key.path, None),
+            None => {
+                if self.key_device(&key.uuid).is_none() {
+                    self.notify(&encrypted.device.to_owned(), Notice::WaitingForKey(&encrypted.device.device, &key.uuid), &[]);
+                }
+
+                self.waiting_for_key.push(WaitingForKey { encrypted, key, deadline });
+            }
+        }
+    }
+
+    fn queue_unlock_with_key_device(&mut self, encrypted: Encrypted, key_device: Filesystem, path: String, reply: Option<Reply<String>>) {
+        let device = encrypted.device.to_owned();
+        let key_device_path = key_device.device.object_path.to_string();
+        let action = Action::UnlockWithKeyDevice { encrypted, key_device: Box::new(key_device), path };
+
+        self.notify(&device, Notice::Unlocking(&device.device), &[]);
+        self.unlocking.insert(device.object_path.to_string());
+        self.queue(action, Some(DBUS_TIMEOUT), Pending::Unlock { device, attempt: None, key_device: Some(key_device_path), reply });
+    }
+
+    fn key_device(&self, uuid: &str) -> Option<Filesystem> {
+        key_device(&self.devices, uuid)
+    }
+
+    /// Unlocks the devices waiting for the keyfiles on `device`, returns
+    /// false if none were
+    fn key_device_attached(&mut self, device: &Block) -> bool {
+        let uuid = match &device.uuid {
+            Some(uuid) => uuid,
+            None => return false
+        };
+
+        let (waiting, others): (Vec<WaitingForKey>, Vec<WaitingForKey>) = self.waiting_for_key.drain(..)
+            .partition(|waiting| &waiting.key.uuid == uuid);
+        self.waiting_for_key = others;
+
+        let attached = !waiting.is_empty();
+
+        for waiting in waiting {
+            self.unlock_with_key_device(waiting.encrypted, waiting.key, waiting.deadline);
+        }
+
+        attached
+    }
+
+    /// Gives up on devices whose keyfile's device wasn't attached in time
+    fn expire_waiting_for_key(&mut self, now: Instant) {
+        let (expired, waiting): (Vec<WaitingForKey>, Vec<WaitingForKey>) = self.waiting_for_key.drain(..)
+            .partition(|waiting| waiting.deadline <= now);
+        self.waiting_for_key = waiting;
+
+        for waiting in expired {
+            let e = MounterError::KeyDeviceMissing(waiting.key.uuid);
+            eprintln!("{}", e);
+            self.notify(&waiting.encrypted.device, Notice::DecryptFail(&e.to_string()), &[]);
+        }
+    }
+
+    fn queue(&mut self, action: Action, timeout: Option<Duration>, pending: Pending) {
+        let queued = self.pool.submit(action, timeout);
+        self.jobs.insert(queued.id, (queued, pending));
+    }
+
+    /// Whether udman is mounting, unlocking or asking for the password of a
+    /// device. Hooks aren't counted as they don't change the device
+    fn has_job(&self, object_path: &str) -> bool {
+        self.jobs.values()
+            .filter(|(_, pending)| !matches!(pending, Pending::Hook { .. }))
+            .any(|(_, pending)| pending.is_for(object_path) || pending.key_device() == Some(object_path))
+    }
+
+    /// Hooks for a device, falling back to the ones in `[settings]`
+    fn hooks_for(&self, device: &Block) -> Hooks {
+        let settings = self.settings_for(device);
+        let mut hooks = settings.hooks;
+
+        // `run` is the older name for a device's mount hook
+        if hooks.on_mount.is_none() {
+            hooks.on_mount = settings.run;
+        }
+
+        hooks.merge(&self.config.settings.hooks)
+    }
+
+    /// Runs a hook's script for a device in the background, if there is one
+    fn run_hook(&mut self, hook: Hook, device: &Block, mount_path: Option<&str>, error: Option<&str>) {
+        let hooks = self.hooks_for(device);
+
+        if let Some(script) = hooks.script(hook) {
+            let script = script.to_owned();
+            let timeout = hooks.timeout.map(Duration::from_secs).unwrap_or(hooks::DEFAULT_TIMEOUT);
+            let env = hooks::environment(hook, device, self.drive_for(device), mount_path, error);
+            let action = Action::Hook { script: script.to_owned(), env, input: None, timeout };
+
+            // Scripts kill themselves once they run out of time
+            self.queue(action, None, Pending::Hook { device: device.to_owned(), hook, script });
+        }
+    }
+
+    /// Acts on jobs that have finished and gives up on ones that have taken
+    /// too long. Should be called regularly from the event loop
+    pub fn process_jobs(&mut self) {
+        for finished in self.pool.finished() {
+            if let Some((_, pending)) = self.jobs.remove(&finished.id) {
+                let device = pending.freed_device();
+
+                self.job_finished(pending, finished.outcome);
+
+                if let Some(device) = device {
+                    self.device_free(&device);
+                }
             }
         }
 
-        Notifier::notify(Notice::NewEncrypted(&encrypted.device.device)).ok();
+        let now = Instant::now();
+        self.expire_waiting_for_key(now);
+
+        let overdue: Vec<JobId> = self.jobs.iter()
+            .filter(|(_, (queued, _))| queued.is_overdue(now))
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in overdue {
+            if let Some((queued, pending)) = self.jobs.remove(&id) {
+                let device = pending.freed_device();
 
-        let mut keyfile_path = None;
-        let mut password = None;
+                queued.cancel();
+                self.job_timed_out(pending);
 
-        if let Some(encrypted_config) = self.config.get_uuid_settings(&encrypted.device.uuid.as_ref().unwrap()) {
-            keyfile_path = encrypted_config.keyfile.to_owned();
-            password = encrypted_config.password.to_owned();
+                if let Some(device) = device {
+                    self.device_free(&device);
+                }
+            }
         }
+    }
 
-        encrypted
-            .unlock(keyfile_path, password.or(self.password_prompt()))
-            .map_or_else(
-                |e| {
-                    eprintln!("{}", e);
-                    Notifier::notify(Notice::DecryptFail(&e.to_string())).ok();
-                },
-                |path| {
-                    Notifier::notify(Notice::DecryptSuccess(&path)).ok();
+    /// Devices waiting for keyfiles on a device udman was busy with can go
+    /// ahead once it's done, one at a time so that they don't race to mount
+    /// and unmount it
+    fn device_free(&mut self, object_path: &str) {
+        if self.has_job(object_path) {
+            return;
+        }
+
+        if let Some(device) = self.devices.get(object_path).cloned() {
+            self.key_device_attached(&device);
+        }
+    }
+
+    fn job_finished(&mut self, pending: Pending, outcome: Outcome) {
+        match (pending, outcome) {
+            (Pending::PreMount { filesystem, unlocked, options, fstype, script }, Outcome::HookFinished(result)) => {
+                if let Ok(output) = &result {
+                    hooks::log_output(Hook::PreMount, &script, output);
+                }
+
+                match Verdict::from_output(result) {
+                    Verdict::Mount => self.queue_mount(filesystem, unlocked, options, fstype),
+                    Verdict::ReadOnly => {
+                        self.queue_mount(filesystem, unlocked, Some(with_read_only(options)), fstype);
+                    },
+                    Verdict::Options(options) => self.queue_mount(filesystem, unlocked, Some(options), fstype),
+                    Verdict::Ignore(reason) => {
+                        let device = filesystem.device;
+                        self.dismissed.insert(device.object_path.to_string());
+
+                        if unlocked {
+                            self.notify(&device, Notice::DecryptSuccess(&device.device), &[]);
+                        }
+
+                        self.notify(&device, Notice::MountVetoed(&device.device, &reason), &[]);
+                    }
                 }
-            );
+            },
+            (Pending::Mount { device, unlocked, reply }, Outcome::Mounted(result)) => {
+                let reply_result = match &result {
+                    Ok(mount_path) => Ok(mount_path.to_owned()),
+                    Err(e) => Err(MounterError::MountFailed(device.device.to_owned(), e.message().unwrap_or("D-Bus error").to_owned()))
+                };
+
+                self.mount_finished(device, unlocked, result);
+                send_reply(reply, reply_result);
+            },
+            (Pending::Unlock { device, attempt, reply, .. }, Outcome::Unlocked(result)) => {
+                match result {
+                    Ok(path) => {
+                        // Remember the cleartext device so the property change
+                        // that follows isn't reported a second time
+                        self.update_device(&device.object_path, |device| {
+                            if let Some(enc_info) = &mut device.enc_info {
+                                enc_info.cleartext_device = Some(path.to_owned());
+                            }
+                        });
+
+                        self.run_hook(Hook::Unlock, &device, None, None);
+                        send_reply(reply, Ok(path));
+                    },
+                    Err(e) => {
+                        self.unlocking.remove(&device.object_path.to_string());
+
+                        // A passphrase the user typed may just be mistyped
+                        let retry = attempt.filter(|attempt| *attempt < self.unlock_attempts())
+                            .and_then(|attempt| Some((attempt, device.as_enc()?)));
+
+                        match retry {
+                            // Nothing was saved in the keyring, or what was
+                            // saved no longer works
+                            Some((0, encrypted)) => {
+                                if !matches!(e, MounterError::NoKeyProvided) {
+                                    eprintln!("The passphrase saved for {} didn't work: {}", device.device, e);
+                                }
+
+                                self.notify(&device, Notice::NewEncrypted(&device.device), &[]);
+                                self.prompt(encrypted, 1, None);
+                            },
+                            Some((attempt, encrypted)) => {
+                                eprintln!("{}", e);
+                                self.prompt(encrypted, attempt + 1, Some(e.to_string()));
+                            },
+                            None => {
+                                eprintln!("{}", e);
+                                self.notify(&device, Notice::DecryptFail(&e.to_string()), &[]);
+                                send_reply(reply, Err(e));
+                            }
+                        }
+                    }
+                }
+            },
+            (Pending::Prompt { encrypted, attempt }, Outcome::Prompted(answer)) => {
+                match answer {
+                    Ok(Some(answer)) => {
+                        let keys = FsSettings { password: Some(answer.passphrase), ..FsSettings::default() };
+                        self.queue_unlock(encrypted, keys, Some(attempt), answer.remember, None);
+                    },
+                    Ok(None) => {
+                        self.notify(&encrypted.device, Notice::DecryptFail(&MounterError::NoKeyProvided.to_string()), &[]);
+                    },
+                    Err(e) => {
+                        eprintln!("Couldn't ask for the passphrase of {}: {}", encrypted.device.device, e);
+                        self.notify(&encrypted.device, Notice::DecryptFail(&e), &[]);
+                    }
+                }
+            },
+            (Pending::Hook { hook, script, .. }, Outcome::HookFinished(result)) => {
+                match result {
+                    Ok(output) => hooks::log_output(hook, &script, &output),
+                    Err(e) => eprintln!("{} {} failed: {}", hook.name(), script, e)
+                }
+            },
+            (Pending::Unmount { device, lock, reply }, Outcome::Unmounted(result)) => {
+                match result {
+                    Ok(()) => {
+                        let mount_path = mount_path(&device).unwrap_or(&device.device).to_owned();
+
+                        self.unmounted(&device, &mount_path);
+                        self.notify(&device, Notice::UnmountSuccess(&device.device), &[]);
+
+                        match lock {
+                            Some(encrypted) => self.queue_lock(encrypted, reply),
+                            None => send_reply(reply, Ok(()))
+                        }
+                    },
+                    Err(e) => send_reply(reply, Err(e))
+                }
+            },
+            (Pending::Lock { device, reply }, Outcome::Locked(result)) => {
+                match result {
+                    Ok(()) => {
+                        self.locked(&device);
+                        self.notify(&device, Notice::Locked(&device.device), &[]);
+                        send_reply(reply, Ok(()));
+                    },
+                    Err(e) => send_reply(reply, Err(MounterError::LockFailed(device.device.to_owned(), e)))
+                }
+            },
+            (Pending::Eject { ejecting, step }, outcome) => self.eject_step_finished(ejecting, step, outcome),
+            _ => ()
+        }
     }
 
-    fn password_prompt(&self) -> Option<String> {
-        dialog::Password::new("Enter password")
-            .title("Encrypted Device")
-            .show()
-            .unwrap_or(None)
+    fn job_timed_out(&mut self, pending: Pending) {
+        match pending {
+            Pending::PreMount { filesystem, .. } => {
+                self.notify(&filesystem.device, Notice::MountVetoed(&filesystem.device.device, "pre_mount hook timed out"), &[]);
+            },
+            Pending::Mount { device, reply, .. } => {
+                self.notify(&device, Notice::MountFail(&device.device, "Timed out"), &[]);
+                self.run_hook(Hook::MountFailed, &device, None, Some("Timed out"));
+                send_reply(reply, Err(MounterError::TimedOut(format!("mounting {}", device.device))));
+            },
+            Pending::Unlock { device, reply, .. } => {
+                let e = MounterError::TimedOut(format!("unlocking {}", device.device));
+
+                self.unlocking.remove(&device.object_path.to_string());
+                self.notify(&device, Notice::DecryptFail(&e.to_string()), &[]);
+                send_reply(reply, Err(e));
+            },
+            Pending::Prompt { encrypted, .. } => {
+                self.notify(&encrypted.device, Notice::DecryptFail(&format!("No password entered for {}", encrypted.device.device)), &[]);
+            },
+            Pending::Hook { hook, script, .. } => {
+                eprintln!("{} {} timed out", hook.name(), script);
+            },
+            Pending::Unmount { device, reply, .. } => {
+                send_reply(reply, Err(MounterError::TimedOut(format!("unmounting {}", device.device))));
+            },
+            Pending::Lock { device, reply } => {
+                send_reply(reply, Err(MounterError::TimedOut(format!("locking {}", device.device))));
+            },
+            Pending::Eject { ejecting, .. } => {
+                let e = MounterError::TimedOut(format!("ejecting {}", ejecting.drive.name()));
+                self.eject_failed(ejecting, e);
+            }
+        }
     }
 
-    fn new_filesystem(&mut self, filesystem: Filesystem) {
+    /// Cancels any jobs for a device that's gone away. Ejects carry on as
+    /// locking and powering off remove the drive's devices
+    fn cancel_jobs(&mut self, object_path: &str) {
+        let cancelled: Vec<JobId> = self.jobs.iter()
+            .filter(|(_, (_, pending))| pending.device().map(|device| &*device.object_path) == Some(object_path))
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in cancelled {
+            if let Some((queued, _)) = self.jobs.remove(&id) {
+                queued.cancel();
+            }
+        }
+
+        self.unlocking.remove(object_path);
+        self.waiting_for_key.retain(|waiting| &*waiting.encrypted.device.object_path != object_path);
+    }
+
+    /// Settings resolved from the device's UUID section and any matching
+    /// rule, along with those of the encrypted device backing it
+    fn settings_for(&self, device: &Block) -> FsSettings {
+        self.config.settings_for(device, self.backing_device(device), self.drive_for(device))
+    }
+
+    fn backing_device(&self, device: &Block) -> Option<&Block> {
+        backing_device(&self.devices, device)
+    }
+
+    fn drive_for(&self, device: &Block) -> Option<&Drive> {
+        drive_for(&self.devices, &self.drives, device)
+    }
+
+    fn is_removable(&self, device: &Block) -> bool {
         // Don't do anything if the drive isn't removable
-        if let Some(drive) = self.drives.get(filesystem.device.drive.as_ref().unwrap()) {
+        if let Some(drive) = self.drive_for(device) {
             if !drive.removable {
-                return;
+                return false;
             }
         }
 
+        true
+    }
+
+    fn new_filesystem(&mut self, filesystem: Filesystem) {
         // Don't alert and mount if it's already mounted
-        if let Some(fs_info) = &filesystem.device.fs_info {
-            if fs_info.mount_paths.is_some() {
-                return;
+        if !self.is_removable(&filesystem.device) || is_mounted(&filesystem.device) {
+            return;
+        }
+
+        // Choices can only be remembered for devices with a UUID
+        let has_uuid = filesystem.device.uuid.is_some();
+        let mut actions = Vec::new();
+
+        if has_uuid {
+            actions.push(("never", "Never mount"));
+        }
+
+        if !self.apply_fs_settings(filesystem.to_owned(), false, &actions) {
+            let device = filesystem.device;
+            let mut actions = vec!(("mount", "Mount"), ("mount-ro", "Mount read-only"), ("ignore", "Ignore"));
+
+            if has_uuid {
+                actions.push(("always", "Always mount"));
             }
+
+            self.publish_decision(&device, "ask");
+            self.notify(&device, Notice::NewFilesystem(&device.device), &actions);
+        }
+    }
+
+    fn automount(&self, device: &Block) -> bool {
+        self.settings_for(device).automount.unwrap_or(self.config.settings.automount)
+    }
+
+    /// Shows a notice in the notification for the drive the device is on,
+    /// replacing the last notice shown for the device. Cleartext devices
+    /// share the notification of the encrypted device backing them. Actions
+    /// are pairs of action names and button labels, pressing a button calls
+    /// `notification_action` with the action name and the device
+    fn notify(&mut self, device: &Block, notice: Notice, actions: &[(&str, &str)]) {
+        let member = self.backing_device(device).unwrap_or(device);
+        let drive = self.drive_for(device).map(|drive| (drive.object_path.to_string(), drive.name()));
+        let (group, title) = match &drive {
+            Some((object_path, name)) => (object_path.to_owned(), name.to_owned()),
+            None => (member.object_path.to_string(), device_name(member).to_owned())
+        };
+
+        let actions: Vec<(String, String)> = actions.iter()
+            .map(|(action, label)| (format!("{}:{}", action, device.object_path), label.to_string()))
+            .collect();
+
+        let member_path = member.object_path.to_string();
+        let label = device_name(member).to_owned();
+        let details = Details { drive: drive.as_ref().map(|(_, name)| name.as_str()), ..details(device) };
+
+        self.publish(Some(device), &notice, &details);
+
+        let notification = match notice.render(&self.config.notifications, details) {
+            Some(notification) => notification,
+            None => return
+        };
+
+        self.notifier.show(&group, &title, &member_path, &label, notification, &actions).ok();
+    }
+
+    /// Shows a notice about a whole drive in the drive's notification
+    fn notify_drive(&mut self, drive: &Drive, notice: Notice) {
+        let name = drive.name();
+        let details = Details { drive: Some(&name), ..Details::default() };
+
+        self.publish(None, &notice, &details);
+
+        if let Some(notification) = notice.render(&self.config.notifications, details) {
+            self.notifier.show_group(&drive.object_path, &drive.name(), notification).ok();
         }
- 
-        Notifier::notify(Notice::NewFilesystem(&filesystem.device.device)).ok();
+    }
 
-        let mut should_mount = None;
-        let mut script = None;
+    /// Publishes the events a notice stands for, whether or not the notice
+    /// is shown
+    fn publish(&self, device: Option<&Block>, notice: &Notice, details: &Details) {
+        let details = notice.details(details.clone());
 
-        if let Some(fs_config) = self.config.get_uuid_settings(&filesystem.device.uuid.as_ref().unwrap()) {
-            should_mount = fs_config.automount;
-            script = fs_config.run.to_owned();
+        for event in notice.events() {
+            self.publisher.publish(&Event::new(event, device, &details));
         }
+    }
 
-        if should_mount.unwrap_or(self.config.settings.automount) {
-            match filesystem.mount() {
-                Ok(mount_path) => {
-                    Notifier::notify(Notice::MountSuccess(&mount_path)).ok();
+    /// Publishes what udman decided to do with a new device under its
+    /// settings, `mount`, `ask`, `unlock` or `prompt`
+    fn publish_decision(&self, device: &Block, decision: &'static str) {
+        let details = details(device);
 
-                    if let Some(script) = script {
-                        if std::path::Path::new(&script).exists() {
-                            std::process::Command::new(script).output().expect("failed to execute command");
-                        }
-                    }
-                },
-                Err(e) => {
-                    eprintln!("{:#?}", e);
-                    Notifier::notify(Notice::MountFail(&filesystem.device.device)).ok();
+        self.publisher.publish(&Event { decision: Some(decision), ..Event::new("policy", Some(device), &details) });
+    }
+
+    /// Reports a config file that couldn't be loaded or saved
+    pub fn config_error(&mut self, e: &ConfigError) {
+        if let Some(notification) = Notice::ConfigError(&e.to_string()).render(&self.config.notifications, Details::default()) {
+            self.notifier.notify(notification).ok();
+        }
+    }
+
+    /// Takes a device out of its drive's notification
+    fn forget(&mut self, device: &Block) {
+        let member = self.backing_device(device).unwrap_or(device).object_path.to_string();
+        let group = self.drive_for(device).map(|drive| drive.object_path.to_string()).unwrap_or_else(|| member.to_owned());
+
+        self.notifier.remove(&group, &member).ok();
+    }
+
+    fn notify_mounted(&mut self, device: &Block, mount_path: &str) {
+        self.notify(device, Notice::MountSuccess(mount_path), &[("open", "Open"), ("eject", "Eject")]);
+    }
+
+    /// Carries out the action for a notification button that was pressed,
+    /// `key` is the action name and the device's object path
+    pub fn notification_action(&mut self, key: &str) {
+        let mut parts = key.splitn(2, ':');
+        let (action, object_path) = match (parts.next(), parts.next()) {
+            (Some(action), Some(object_path)) => (action, object_path),
+            _ => return
+        };
+
+        let device = match self.devices.get(object_path) {
+            Some(device) => device.to_owned(),
+            None => return
+        };
+
+        match action {
+            "mount" | "mount-ro" => {
+                if let Some(filesystem) = device.as_fs().filter(|filesystem| !is_mounted(&filesystem.device)) {
+                    self.notify(&device, Notice::Mounting(&device.device), &[]);
+                    self.start_mount(filesystem, false, action == "mount-ro");
+                }
+            },
+            "ignore" => {
+                self.dismissed.insert(device.object_path.to_string());
+                self.forget(&device);
+            },
+            "open" => {
+                if let Some(mount_path) = mount_path(&device) {
+                    open(mount_path);
+                }
+            },
+            "eject" => {
+                if let Some(drive) = self.drive_for(&device).map(|drive| drive.object_path.to_string()) {
+                    // Failures are reported by eject itself
+                    self.eject(&drive, false, None);
+                }
+            },
+            "always" | "never" => {
+                if let Some(uuid) = &device.uuid {
+                    self.remember_automount(uuid, action == "always");
+                }
+            },
+            _ => ()
+        }
+    }
+
+    /// Saves whether a device should always or never be mounted to the
+    /// config file and applies the change to the device straight away.
+    /// Other devices are left as they are
+    pub fn remember_automount(&mut self, uuid: &str, automount: bool) {
+        if let Err(e) = Config::remember_automount(&self.config_path, uuid, automount) {
+            eprintln!("Could not save settings for {}: {}", uuid, e);
+            self.config_error(&e);
+            return;
+        }
+
+        self.written_config = fs::read_to_string(&self.config_path).ok();
+        self.config.uuid.get_or_insert_with(HashMap::new)
+            .entry(uuid.to_owned())
+            .or_default()
+            .automount = Some(automount);
+
+        let filesystems: Vec<Filesystem> = self.devices.values()
+            .filter(|device| device.uuid.as_deref() == Some(uuid))
+            .filter_map(Block::as_fs)
+            .collect();
+
+        for filesystem in filesystems {
+            let object_path = filesystem.device.object_path.to_string();
+
+            self.automount.insert(object_path.to_owned(), automount);
+            self.dismissed.remove(&object_path);
+
+            if automount && self.is_removable(&filesystem.device) && !is_mounted(&filesystem.device) && !self.has_job(&object_path) {
+                self.apply_fs_settings(filesystem, false, &[]);
+            }
+        }
+    }
+
+    /// Forgets about a notification once it's been closed
+    pub fn notification_closed(&mut self, id: u32) {
+        self.notifier.closed(id);
+    }
+
+    /// Mounts the filesystem inside a device udman just unlocked, reporting
+    /// the unlock and the mount in a single notification
+    fn unlocked_filesystem(&mut self, filesystem: Filesystem) {
+        let device = filesystem.device.to_owned();
+
+        if !self.is_removable(&device) || is_mounted(&device) || !self.apply_fs_settings(filesystem, true, &[]) {
+            self.notify(&device, Notice::DecryptSuccess(&device.device), &[]);
+        }
+    }
+
+    /// Queues a mount if the settings say so, its hook is run once it's
+    /// mounted. If there's a `pre_mount` hook it's asked first and decides
+    /// whether and how the filesystem is mounted. `unlocked` is set for
+    /// filesystems udman has just unlocked so the notification covers both.
+    /// `actions` are offered in the notification that it's being mounted.
+    /// Returns true if a mount was queued
+    fn apply_fs_settings(&mut self, filesystem: Filesystem, unlocked: bool, actions: &[(&str, &str)]) -> bool {
+        if !self.automount(&filesystem.device) {
+            return false;
+        }
+
+        let device = filesystem.device.to_owned();
+
+        self.publish_decision(&device, "mount");
+        self.notify(&device, Notice::Mounting(&device.device), actions);
+        self.start_mount(filesystem, unlocked, false);
+
+        true
+    }
+
+    /// Mounts a filesystem, asking the `pre_mount` hook first if there is one
+    fn start_mount(&mut self, filesystem: Filesystem, unlocked: bool, read_only: bool) {
+        let mut settings = self.settings_for(&filesystem.device);
+        let hooks = self.hooks_for(&filesystem.device);
+
+        if read_only {
+            settings.options = Some(with_read_only(settings.options));
+        }
+
+        match hooks.pre_mount {
+            Some(script) => {
+                let device = &filesystem.device;
+                let drive = self.drive_for(device);
+                let info = DeviceInfo::new(device, drive, settings.options.as_deref());
+                let action = Action::Hook {
+                    script: script.to_owned(),
+                    env: hooks::environment(Hook::PreMount, device, drive, None, None),
+                    input: serde_json::to_string(&info).ok(),
+                    timeout: hooks.timeout.map(Duration::from_secs).unwrap_or(hooks::DEFAULT_TIMEOUT)
+                };
+
+                let pending = Pending::PreMount { filesystem, unlocked, options: settings.options, fstype: settings.fstype, script };
+
+                self.queue(action, None, pending);
+            },
+            None => self.queue_mount(filesystem, unlocked, settings.options, settings.fstype)
+        }
+    }
+
+    fn queue_mount(&mut self, filesystem: Filesystem, unlocked: bool, options: Option<String>, fstype: Option<String>) {
+        let pending = Pending::Mount { device: filesystem.device.to_owned(), unlocked, reply: None };
+
+        self.queue(Action::Mount { filesystem, options, fstype }, Some(DBUS_TIMEOUT), pending);
+    }
+
+    fn mount_finished(&mut self, device: Block, unlocked: bool, result: Result<String, dbus::Error>) {
+        match result {
+            Ok(mount_path) => {
+                if unlocked {
+                    self.notify(&device, Notice::UnlockedAndMounted(&mount_path), &[("open", "Open"), ("eject", "Eject")]);
+                } else {
+                    self.notify_mounted(&device, &mount_path);
                 }
+
+                // Remember the mount so a config reload doesn't try to
+                // mount it a second time
+                self.update_device(&device.object_path, |device| {
+                    if let Some(fs_info) = &mut device.fs_info {
+                        fs_info.mount_paths = Some(vec!(mount_path.to_owned()));
+                    }
+                });
+
+                self.run_hook(Hook::Mount, &device, Some(&mount_path), None);
+            },
+            Err(e) => {
+                eprintln!("{:#?}", e);
+
+                let reason = e.message().unwrap_or("D-Bus error");
+
+                self.notify(&device, Notice::MountFail(&device.device, reason), &[]);
+                self.run_hook(Hook::MountFailed, &device, None, Some(reason));
             }
         }
     }
 
-    /// Removes devices from memory. If the removed device was a filesystem
-    /// then a notification is sent with information
+    /// Devices udman knows about and the drive each one is on
+    pub fn devices(&self) -> Vec<(&Block, Option<&Drive>)> {
+        devices(&self.devices, &self.drives)
+    }
+
+    /// A device udman knows about, found the way `find_device` finds it
+    pub fn find_device(&self, name: &str) -> Option<&Block> {
+        find_device(&self.devices, name)
+    }
+
+    /// A drive udman knows about, found the way `find_drive` finds it
+    pub fn find_drive(&self, name: &str) -> Option<&Drive> {
+        find_drive(&self.devices, &self.drives, name)
+    }
+
+    /// Mounts a filesystem in the background with its configured options,
+    /// `pre_mount` hooks aren't asked. `reply` is told where it's mounted, a
+    /// filesystem that's already mounted is left where it is
+    pub fn mount(&mut self, name: &str, reply: Reply<String>) {
+        let filesystem = match find_filesystem(&self.devices, name).map(|device| device.as_fs()) {
+            Ok(Some(filesystem)) => filesystem,
+            Ok(None) => return reply.send(Err(MounterError::NotFilesystem(name.to_owned()))),
+            Err(e) => return reply.send(Err(e))
+        };
+
+        if let Some(mount_path) = mount_path(&filesystem.device) {
+            return reply.send(Ok(mount_path.to_owned()));
+        }
+
+        let settings = self.settings_for(&filesystem.device);
+        let pending = Pending::Mount { device: filesystem.device.to_owned(), unlocked: false, reply: Some(reply) };
+        let action = Action::Mount { filesystem, options: settings.options, fstype: settings.fstype };
+
+        self.queue(action, Some(DBUS_TIMEOUT), pending);
+    }
+
+    /// Unmounts a filesystem in the background, nothing is done if it isn't
+    /// mounted. `reply` is told once it's done
+    pub fn unmount(&mut self, name: &str, reply: Reply<()>) {
+        let device = match find_filesystem(&self.devices, name) {
+            Ok(device) => device.to_owned(),
+            Err(e) => return reply.send(Err(e))
+        };
+
+        self.queue_unmount(device, None, reply);
+    }
+
+    /// Unmounts the filesystem on `device` if it's mounted, and then locks
+    /// `lock` if it's given
+    fn queue_unmount(&mut self, device: Block, lock: Option<Encrypted>, reply: Reply<()>) {
+        let filesystem = match (mount_path(&device), device.as_fs()) {
+            (Some(_), Some(filesystem)) => filesystem,
+            _ => return match lock {
+                Some(encrypted) => self.queue_lock(encrypted, Some(reply)),
+                None => reply.send(Ok(()))
+            }
+        };
+
+        let pending = Pending::Unmount { device, lock, reply: Some(reply) };
+
+        self.queue(Action::Unmount { filesystem, lazy: false }, Some(DBUS_TIMEOUT), pending);
+    }
+
+    /// Records a filesystem udman unmounted and runs its hook
+    fn unmounted(&mut self, device: &Block, mount_path: &str) {
+        // Recorded straight away so the property change that follows isn't
+        // reported a second time
+        self.update_device(&device.object_path, |device| {
+            if let Some(fs_info) = &mut device.fs_info {
+                fs_info.mount_paths = None;
+            }
+        });
+
+        self.dismissed.insert(device.object_path.to_string());
+        self.run_hook(Hook::Unmount, device, Some(mount_path), None);
+    }
+
+    /// Records an encrypted device udman locked
+    fn locked(&mut self, device: &Block) {
+        self.update_device(&device.object_path, |device| {
+            if let Some(enc_info) = &mut device.enc_info {
+                enc_info.cleartext_device = None;
+            }
+        });
+    }
+
+    /// Unlocks an encrypted device in the background, the key is chosen the
+    /// way `unlock_with` chooses it. The filesystem inside is then handled
+    /// like any other device udman unlocks. `reply` is told the object path
+    /// of the cleartext device
+    pub fn unlock_device(&mut self, name: &str, passphrase: Option<String>, remember: bool, reply: Reply<String>) {
+        match unlock_with(&self.config, &self.devices, &self.drives, name, passphrase, remember) {
+            Ok(UnlockWith::Unlocked(cleartext_device)) => reply.send(Ok(cleartext_device)),
+            Ok(UnlockWith::Keys { encrypted, keys, remember }) => self.queue_unlock(encrypted, keys, None, remember, Some(reply)),
+            Ok(UnlockWith::KeyDevice { encrypted, key_device, path }) => {
+                self.queue_unlock_with_key_device(encrypted, key_device, path, Some(reply));
+            },
+            Err(e) => reply.send(Err(e))
+        }
+    }
+
+    /// Locks an encrypted device in the background, unmounting the
+    /// filesystem inside first. `reply` is told once it's done
+    pub fn lock_device(&mut self, name: &str, reply: Reply<()>) {
+        let device = match self.find_device(name) {
+            Some(device) => device.to_owned(),
+            None => return reply.send(Err(MounterError::UnknownDevice(name.to_owned())))
+        };
+
+        let encrypted = match device.as_enc() {
+            Some(encrypted) => encrypted,
+            None => return reply.send(Err(MounterError::NotEncrypted(name.to_owned())))
+        };
+
+        let cleartext_device = match device.enc_info.as_ref().and_then(|enc_info| enc_info.cleartext_device.as_ref()) {
+            Some(cleartext_device) => cleartext_device,
+            None => return reply.send(Ok(()))
+        };
+
+        match self.devices.get(cleartext_device).cloned() {
+            Some(cleartext_device) => self.queue_unmount(cleartext_device, Some(encrypted), reply),
+            None => self.queue_lock(encrypted, Some(reply))
+        }
+    }
+
+    fn queue_lock(&mut self, encrypted: Encrypted, reply: Option<Reply<()>>) {
+        let pending = Pending::Lock { device: encrypted.device.to_owned(), reply };
+
+        self.queue(Action::Lock { encrypted }, Some(DBUS_TIMEOUT), pending);
+    }
+
+    /// Reloads the config file after it's changed, unless the change is udman
+    /// saving a choice that's already been applied
+    pub fn config_file_changed(&mut self) {
+        let written = self.written_config.take();
+
+        if written.is_some() && written == fs::read_to_string(&self.config_path).ok() {
+            return;
+        }
+
+        // Failures are reported by reload itself
+        self.reload().ok();
+    }
+
+    /// Loads the config file again, the current configuration is kept if the
+    /// new one can't be used
+    pub fn reload(&mut self) -> Result<(), ConfigError> {
+        match Config::load(&self.config_path) {
+            Ok(config) => {
+                self.reload_config(config);
+                Ok(())
+            },
+            Err(e) => {
+                eprintln!("Could not reload config file: {}", e);
+                self.config_error(&e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Device events as they're published, as JSON
+    pub fn subscribe(&mut self) -> Receiver<String> {
+        self.publisher.subscribe()
+    }
+
+    /// Safely ejects a drive in the background, found the way `find_drive`
+    /// finds it. Every filesystem on the drive is unmounted, any unlocked
+    /// encrypted devices are locked and then the drive is powered off, or its
+    /// media ejected, so that it can be removed. Each step is reported with a
+    /// notification, if one fails the drive is left as it is and the error
+    /// names what's blocking the eject. A lazy eject unmounts filesystems even
+    /// if processes are still using them. `reply` is told once it's done
+    pub fn eject(&mut self, name: &str, lazy: bool, reply: Option<Reply<()>>) {
+        let drive = match self.find_drive(name) {
+            Some(drive) => drive.to_owned(),
+            None => return send_reply(reply, Err(MounterError::UnknownDrive(name.to_owned())))
+        };
+
+        let (devices, steps) = eject_steps(&self.devices, &drive);
+
+        self.next_eject_step(Ejecting { drive, devices, steps, lazy, reply });
+    }
+
+    fn next_eject_step(&mut self, mut ejecting: Ejecting) {
+        let step = match ejecting.steps.pop_front() {
+            Some(step) => step,
+            None => return
+        };
+
+        let action = step.action(&ejecting.drive, ejecting.lazy);
+
+        self.queue(action, Some(DBUS_TIMEOUT), Pending::Eject { ejecting, step });
+    }
+
+    /// Reports a step of an eject and goes on to the next one, the drive is
+    /// left as it is if it failed
+    fn eject_step_finished(&mut self, ejecting: Ejecting, step: EjectStep, outcome: Outcome) {
+        let drive = ejecting.drive.to_owned();
+
+        let result = step.result(&drive, outcome).map(|()| match step {
+            EjectStep::Unmount(filesystem) => {
+                let device = filesystem.device;
+                let mount_path = mount_path(&device).unwrap_or(&device.device).to_owned();
+
+                self.unmounted(&device, &mount_path);
+                self.notify_drive(&drive, Notice::EjectStep(&format!("Unmounted {}", mount_path)));
+            },
+            EjectStep::Lock(encrypted) => {
+                let device = encrypted.device;
+
+                self.locked(&device);
+                self.notify_drive(&drive, Notice::EjectStep(&format!("Locked {}", device.device)));
+            },
+            EjectStep::PowerOff => self.notify_drive(&drive, Notice::SafeToRemove(&drive.name()))
+        });
+
+        match result {
+            Ok(()) if ejecting.steps.is_empty() => send_reply(ejecting.reply, Ok(())),
+            Ok(()) => self.next_eject_step(ejecting),
+            Err(e) => self.eject_failed(ejecting, e)
+        }
+    }
+
+    fn eject_failed(&mut self, ejecting: Ejecting, e: MounterError) {
+        eprintln!("{}", e);
+        self.notify_drive(&ejecting.drive, Notice::EjectFail(&ejecting.drive.name(), &e.to_string()));
+        send_reply(ejecting.reply, Err(e));
+    }
+
+    fn update_device<F>(&mut self, object_path: &str, update: F)
+        where F: FnOnce(&mut Block)
+    {
+        if let Some(device) = self.devices.get_mut(object_path) {
+            update(device);
+        }
+    }
+
+    /// Keeps track of changes made to devices outside of udman, such as a
+    /// filesystem being mounted or unmounted by another program, and sends
+    /// a notification describing the change
+    pub fn changed_properties(&mut self, object_path: String, interface: String, properties: Udisks2Properties) {
+        // Changes caused by udman's own jobs are reported when they finish
+        let reported_by_job = self.unlocking.contains(&object_path) || self.has_job(&object_path);
+
+        let device = match self.devices.get_mut(&object_path) {
+            Some(device) => device,
+            None => return
+        };
+
+        if reported_by_job {
+            device.update_properties(&interface, &properties);
+            return;
+        }
+
+        let old_mount_path = mount_path(device).map(str::to_owned);
+        let was_unlocked = is_unlocked(device);
+
+        device.update_properties(&interface, &properties);
+
+        let device = device.to_owned();
+
+        match (old_mount_path, mount_path(&device)) {
+            (None, Some(mount_path)) => {
+                self.notify_mounted(&device, mount_path);
+                self.run_hook(Hook::Mount, &device, Some(mount_path), None);
+            },
+            (Some(old_mount_path), None) => {
+                self.dismissed.insert(device.object_path.to_string());
+                self.notify(&device, Notice::UnmountSuccess(&device.device), &[]);
+                self.run_hook(Hook::Unmount, &device, Some(&old_mount_path), None);
+            },
+            _ => ()
+        }
+
+        match (was_unlocked, device.enc_info.as_ref().and_then(|enc_info| enc_info.cleartext_device.as_ref())) {
+            (false, Some(cleartext_device)) => {
+                self.notify(&device, Notice::DecryptSuccess(cleartext_device), &[]);
+                self.run_hook(Hook::Unlock, &device, None, None);
+            },
+            (true, None) => {
+                self.notify(&device, Notice::Locked(&device.device), &[]);
+            },
+            _ => ()
+        }
+    }
+
+    /// Removes drives and devices from memory and closes their notification.
+    /// Devices that are removed while still mounted or unlocked are reported
+    /// with an urgent warning and recorded in the incident log
     pub fn removed_object(&mut self, object_path: String) {
-        if let Some(device) = self.devices.remove(&object_path) {
-            if let Some(filesystem) = device.as_fs() {
-                Notifier::notify(Notice::UnmountSuccess(&filesystem.device.device)).ok();
+        if self.drives.remove(&object_path).is_some() {
+            self.notifier.close(&object_path).ok();
+        }
+
+        self.cancel_jobs(&object_path);
+
+        if let Some(device) = self.devices.get(&object_path).cloned() {
+            let drive = self.device_drives.remove(&object_path);
+
+            self.forget(&device);
+            self.devices.remove(&object_path);
+            self.automount.remove(&object_path);
+            self.dismissed.remove(&object_path);
+
+            let mount_path = mount_path(&device);
+
+            if mount_path.is_some() || is_unlocked(&device) {
+                self.removed_in_use(&device, drive.as_ref());
+            }
+
+            if device.fs_info.is_some() || device.enc_info.is_some() {
+                self.publisher.publish(&Event::new("removed", Some(&device), &details(&device)));
+                self.run_hook(Hook::Remove, &device, mount_path, None);
             }
         }
     }
+
+    /// Reports a device that was removed while it was mounted or unlocked,
+    /// once for the drive it was on. Cleartext devices can go before or after
+    /// the encrypted device backing them, either one covers the other.
+    /// `drive` is the drive the device was on when it was added, the drive
+    /// itself may already be gone
+    fn removed_in_use(&mut self, device: &Block, drive: Option<&Drive>) {
+        let on_drive = self.backing_device(device).unwrap_or(device).drive.to_owned();
+        let removed: Vec<String> = on_drive.into_iter()
+            .chain(device.crypto_backing_device.to_owned())
+            .chain(Some(device.object_path.to_string()))
+            .collect();
+
+        if removed.iter().any(|removed| self.removed_in_use.contains(removed)) {
+            return;
+        }
+
+        // The filesystem inside an encrypted device is what the user was using
+        let cleartext = device.enc_info.as_ref()
+            .and_then(|enc_info| enc_info.cleartext_device.as_ref())
+            .and_then(|cleartext_device| self.devices.get(cleartext_device))
+            .filter(|cleartext| is_mounted(cleartext))
+            .cloned();
+
+        let unlocked = is_unlocked(device) || device.crypto_backing_device.is_some();
+
+        self.unsafe_removal(cleartext.as_ref().unwrap_or(device), unlocked, drive);
+        self.removed_in_use.extend(removed);
+    }
+
+    fn unsafe_removal(&mut self, device: &Block, unlocked: bool, drive: Option<&Drive>) {
+        let name = device_name(device);
+        let mount_path = mount_path(device);
+
+        let notice = Notice::UnsafeRemoval(name, mount_path.unwrap_or("unlocked encrypted device"));
+
+        let drive_name = drive.map(|drive| drive.name());
+        let details = Details { drive: drive_name.as_deref(), ..details(device) };
+
+        self.publish(Some(device), &notice, &details);
+
+        if let Some(notification) = notice.render(&self.config.notifications, details) {
+            self.notifier.notify(notification).ok();
+        }
+
+        let removal = incidents::UnsafeRemoval {
+            device: &device.device,
+            uuid: device.uuid.as_deref(),
+            label: device.label.as_deref(),
+            mount_path,
+            unlocked,
+            drive_model: drive.and_then(|drive| drive.model.as_deref()),
+            drive_serial: drive.and_then(|drive| drive.serial.as_deref())
+        };
+
+        if let Err(e) = incidents::record(&removal) {
+            eprintln!("Could not record unsafe removal: {}", e);
+        }
+    }
+}
+
+/// Devices and the drive each one is on, in order of their device files
+pub(crate) fn devices<'a>(devices: &'a HashMap<String, Block>, drives: &'a HashMap<String, Drive>) -> Vec<(&'a Block, Option<&'a Drive>)> {
+    let mut devices: Vec<(&Block, Option<&Drive>)> = devices.values()
+        .map(|device| (device, drive_for(devices, drives, device)))
+        .collect();
+
+    devices.sort_by(|(a, _), (b, _)| a.device.cmp(&b.device));
+    devices
+}
+
+/// Finds a device by its object path, device file, one of the device file's
+/// symlinks, UUID or label
+pub(crate) fn find_device<'a>(devices: &'a HashMap<String, Block>, name: &str) -> Option<&'a Block> {
+    devices.values()
+        .filter(|device| device.matches(name))
+        .min_by(|a, b| a.device.cmp(&b.device))
+}
+
+/// Finds a drive by its object path, name or serial number, or by any device
+/// on it
+pub(crate) fn find_drive<'a>(devices: &'a HashMap<String, Block>, drives: &'a HashMap<String, Drive>, name: &str) -> Option<&'a Drive> {
+    drives.values()
+        .find(|drive| drive.matches(name))
+        .or_else(|| find_device(devices, name).and_then(|device| drive_for(devices, drives, device)))
+}
+
+/// The filesystem on a device, for encrypted devices the filesystem inside
+/// once it's unlocked
+pub(crate) fn find_filesystem<'a>(devices: &'a HashMap<String, Block>, name: &str) -> Result<&'a Block, MounterError> {
+    let device = find_device(devices, name).ok_or_else(|| MounterError::UnknownDevice(name.to_owned()))?;
+    let cleartext = device.enc_info.as_ref()
+        .and_then(|enc_info| enc_info.cleartext_device.as_ref())
+        .and_then(|cleartext_device| devices.get(cleartext_device));
+
+    Some(cleartext.unwrap_or(device))
+        .filter(|device| device.as_fs().is_some())
+        .ok_or_else(|| MounterError::NotFilesystem(name.to_owned()))
+}
+
+pub(crate) fn backing_device<'a>(devices: &'a HashMap<String, Block>, device: &Block) -> Option<&'a Block> {
+    device.crypto_backing_device.as_ref().and_then(|backing_device| devices.get(backing_device))
+}
+
+/// The drive a device is on, for cleartext devices this is the drive the
+/// encrypted device is on
+pub(crate) fn drive_for<'a>(devices: &'a HashMap<String, Block>, drives: &'a HashMap<String, Drive>, device: &Block) -> Option<&'a Drive> {
+    let device = backing_device(devices, device).unwrap_or(device);
+
+    device.drive.as_ref().and_then(|drive| drives.get(drive))
+}
+
+/// Works out how to unlock an encrypted device. A passphrase that's given is
+/// used, and saved in the keyring if `remember` is set. Otherwise it's the
+/// configured keyfile, password or password command, or the passphrase saved
+/// in the keyring. A keyfile on another device needs that device attached
+pub(crate) fn unlock_with(config: &Config, devices: &HashMap<String, Block>, drives: &HashMap<String, Drive>, name: &str, passphrase: Option<String>, remember: bool) -> Result<UnlockWith, MounterError> {
+    let device = find_device(devices, name).ok_or_else(|| MounterError::UnknownDevice(name.to_owned()))?;
+    let encrypted = device.as_enc().ok_or_else(|| MounterError::NotEncrypted(name.to_owned()))?;
+
+    if let Some(cleartext_device) = device.enc_info.as_ref().and_then(|enc_info| enc_info.cleartext_device.to_owned()) {
+        return Ok(UnlockWith::Unlocked(cleartext_device));
+    }
+
+    let settings = config.settings_for(device, None, drive_for(devices, drives, device));
+
+    match (passphrase, settings.keyfile) {
+        (Some(passphrase), _) => {
+            let keys = FsSettings { password: Some(passphrase), ..FsSettings::default() };
+            Ok(UnlockWith::Keys { encrypted, keys, remember })
+        },
+        (None, Some(Keyfile::OnDevice(key))) => {
+            // There's someone waiting on the answer so the device holding the
+            // key has to be attached already
+            let key_device = key_device(devices, &key.uuid).ok_or(MounterError::KeyDeviceMissing(key.uuid))?;
+
+            Ok(UnlockWith::KeyDevice { encrypted, key_device, path: key.path })
+        },
+        (None, keyfile) => {
+            let keys = FsSettings { keyfile, ..settings };
+            Ok(UnlockWith::Keys { encrypted, keys, remember: false })
+        }
+    }
+}
+
+/// The filesystem with `uuid`, where keyfiles are read from
+fn key_device(devices: &HashMap<String, Block>, uuid: &str) -> Option<Filesystem> {
+    devices.values()
+        .find(|device| device.uuid.as_deref() == Some(uuid))
+        .and_then(Block::as_fs)
+}
+
+/// Everything on a drive, cleartext devices first, and what has to be done
+/// to eject it. Filesystems are unmounted, then unlocked encrypted devices
+/// are locked and then the drive is powered off
+pub(crate) fn eject_steps(devices: &HashMap<String, Block>, drive: &Drive) -> (Vec<Block>, VecDeque<EjectStep>) {
+    let on_drive: Vec<Block> = devices.values()
+        .filter(|device| device.drive.as_deref() == Some(&drive.object_path))
+        .cloned()
+        .collect();
+
+    // Cleartext devices don't belong to the drive themselves but they're
+    // backed by devices that do
+    let cleartext: Vec<Block> = devices.values()
+        .filter(|device| on_drive.iter().any(|backing| device.crypto_backing_device.as_deref() == Some(&backing.object_path)))
+        .cloned()
+        .collect();
+
+    let devices: Vec<Block> = cleartext.into_iter().chain(on_drive).collect();
+
+    let unmount = devices.iter()
+        .filter(|device| is_mounted(device))
+        .filter_map(Block::as_fs)
+        .map(EjectStep::Unmount);
+
+    let lock = devices.iter()
+        .filter(|device| is_unlocked(device))
+        .filter_map(Block::as_enc)
+        .map(EjectStep::Lock);
+
+    let steps = unmount.chain(lock).chain(Some(EjectStep::PowerOff)).collect();
+
+    (devices, steps)
+}
+
+/// What's known about a device for filling in notification templates and
+/// events, apart from the drive's name which has to be looked up
+pub fn details(device: &Block) -> Details<'_> {
+    Details {
+        label: Some(device_name(device)),
+        device: Some(&device.device),
+        mount_path: mount_path(device),
+        size: Some(device.size).filter(|size| *size > 0),
+        fstype: device.id_type.as_deref(),
+        icon: device.hint_icon_name.as_deref(),
+        ..Details::default()
+    }
+}
+
+/// Name to show for a device, its label if it has one
+fn device_name(device: &Block) -> &str {
+    device.label.as_deref().unwrap_or(&device.device)
+}
+
+fn is_mounted(device: &Block) -> bool {
+    mount_path(device).is_some()
+}
+
+pub fn mount_path(device: &Block) -> Option<&str> {
+    device.fs_info.as_ref()?.mount_paths.as_ref()?.first().map(String::as_str)
+}
+
+fn is_unlocked(device: &Block) -> bool {
+    device.enc_info.as_ref().and_then(|enc_info| enc_info.cleartext_device.as_ref()).is_some()
+}
+
+/// Adds `ro` to mount options
+fn with_read_only(options: Option<String>) -> String {
+    options.map(|options| format!("{},ro", options)).unwrap_or_else(|| "ro".to_owned())
+}
+
+/// Opens a directory in the user's file manager
+fn open(path: &str) {
+    match Command::new("xdg-open").arg(path).spawn() {
+        // Wait in the background so the process doesn't linger once it exits
+        Ok(mut child) => { thread::spawn(move || child.wait()); },
+        Err(e) => eprintln!("Could not open {}: {}", path, e)
+    }
+}
+
+/// Works out which processes are to blame if the filesystem was busy
+pub fn unmount_error(mount_path: &str, error: dbus::Error) -> MounterError {
+    if error.name() == Some("org.freedesktop.UDisks2.Error.DeviceBusy") {
+        MounterError::DeviceBusy(mount_path.to_owned(), processes::using_path(Path::new(mount_path)))
+    } else {
+        MounterError::UnmountFailed(mount_path.to_owned(), error)
+    }
 }
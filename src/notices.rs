@@ -1,53 +1,318 @@
-use crate::notifications::{Notifiable, Notification};
+use crate::config::NotificationSettings;
+use crate::notifications::{Notification, Urgency};
 
 pub enum Notice<'a> {
     NewFilesystem(&'a str),
     NewEncrypted(&'a str),
+    Mounting(&'a str),
+    Unlocking(&'a str),
+    WaitingForKey(&'a str, &'a str),
     MountSuccess(&'a str),
-    MountFail(&'a str),
+    MountFail(&'a str, &'a str),
+    MountVetoed(&'a str, &'a str),
     UnmountSuccess(&'a str),
+    UnsafeRemoval(&'a str, &'a str),
+    EjectStep(&'a str),
+    EjectFail(&'a str, &'a str),
+    SafeToRemove(&'a str),
     DecryptSuccess(&'a str),
-    DecryptFail(&'a str)
+    DecryptFail(&'a str),
+    UnlockedAndMounted(&'a str),
+    Locked(&'a str),
+    ConfigError(&'a str)
 }
 
-impl<'a> Notifiable for Notice<'a> {
-    fn as_notification(self) -> Notification {
-        let mut notification = Notification::default();
+/// Names of each kind of notice as they're written in the config file
+pub const KINDS: [&str; 18] = [
+    "new_filesystem", "new_encrypted", "mounting", "unlocking", "waiting_for_key", "mount_success", "mount_fail", "mount_vetoed",
+    "unmount_success", "unsafe_removal", "eject_step", "eject_fail", "safe_to_remove", "decrypt_success",
+    "decrypt_fail", "unlocked_and_mounted", "locked", "config_error"
+];
 
-        notification.set_app_name("Mounter");
-        notification.set_expire_timeout(-1);
+/// Placeholders that can be used in summary and body templates
+pub const PLACEHOLDERS: [&str; 8] = ["label", "device", "mount_path", "size", "fstype", "error", "drive", "message"];
 
-        match self {
-            Self::NewFilesystem(msg) => {
-                notification.set_summary("New filesystem found");
-                notification.set_body(msg);
+/// Details of the device a notice is about, used to fill in templates
+#[derive(Clone, Default)]
+pub struct Details<'a> {
+    pub label: Option<&'a str>,
+    pub device: Option<&'a str>,
+    pub mount_path: Option<&'a str>,
+    pub size: Option<u64>,
+    pub fstype: Option<&'a str>,
+    pub error: Option<&'a str>,
+    pub drive: Option<&'a str>,
+    pub message: Option<&'a str>,
+    /// Icon UDisks2 suggests for the device
+    pub icon: Option<&'a str>
+}
+
+/// How a kind of notice looks unless the config says otherwise
+struct Template {
+    kind: &'static str,
+    summary: &'static str,
+    body: &'static str,
+    urgency: Option<Urgency>,
+    category: &'static str
+}
+
+impl<'a> Notice<'a> {
+    /// The default template for the notice, anything the notice carries is
+    /// added to `details`
+    fn template(&self, details: &mut Details<'a>) -> Template {
+        let template = |kind, summary, body, category| Template { kind, summary, body, urgency: None, category };
+
+        match *self {
+            Self::NewFilesystem(device) => {
+                details.device = Some(device);
+                template("new_filesystem", "New filesystem found", "{device}", "device.added")
+            },
+            Self::NewEncrypted(device) => {
+                details.device = Some(device);
+                template("new_encrypted", "New encrypted device found", "{device}", "device.added")
+            },
+            Self::Mounting(device) => {
+                details.device = Some(device);
+                template("mounting", "Mounting…", "{device}", "device")
+            },
+            Self::Unlocking(device) => {
+                details.device = Some(device);
+                template("unlocking", "Unlocking…", "{device}", "device")
+            },
+            Self::WaitingForKey(device, uuid) => {
+                details.device = Some(device);
+                details.message = Some(uuid);
+                template("waiting_for_key", "Waiting for key", "Attach the device holding the key for {device} ({message})", "device")
+            },
+            Self::MountSuccess(mount_path) => {
+                details.mount_path = Some(mount_path);
+                template("mount_success", "Filesystem mounted", "{mount_path}", "device")
+            },
+            Self::MountFail(device, reason) => {
+                details.device = Some(device);
+                details.error = Some(reason);
+                template("mount_fail", "Failed to mount", "{device}: {error}", "device.error")
+            },
+            Self::MountVetoed(device, reason) => {
+                details.device = Some(device);
+                details.error = Some(reason);
+                template("mount_vetoed", "Mount blocked", "{device}: {error}", "device.error")
+            },
+            Self::UnmountSuccess(device) => {
+                details.device = Some(device);
+                template("unmount_success", "Filesystem unmounted", "{device}", "device")
+            },
+            Self::UnsafeRemoval(name, mount_path) => {
+                details.label = Some(name);
+                details.mount_path = Some(mount_path);
+
+                Template {
+                    urgency: Some(Urgency::Critical),
+                    ..template("unsafe_removal", "Device removed without ejecting", "{label} was still in use ({mount_path}), data may have been lost", "device.removed")
+                }
             },
-            Self::NewEncrypted(msg) => {
-                notification.set_summary("New encrypted device found");
-                notification.set_body(msg);
+            Self::EjectStep(message) => {
+                details.message = Some(message);
+                template("eject_step", "Ejecting", "{message}", "device")
             },
-            Self::MountSuccess(msg) => {
-                notification.set_summary("Filesystem mounted");
-                notification.set_body(msg);
+            Self::EjectFail(drive, reason) => {
+                details.drive = Some(drive);
+                details.error = Some(reason);
+                template("eject_fail", "Failed to eject", "{drive}: {error}", "device.error")
             },
-            Self::MountFail(msg) => {
-                notification.set_summary("Failed to mount");
-                notification.set_body(msg);
+            Self::SafeToRemove(drive) => {
+                details.drive = Some(drive);
+                template("safe_to_remove", "Safe to remove", "{drive}", "device")
             },
-            Self::UnmountSuccess(msg) => {
-                notification.set_summary("Filesystem unmounted");
-                notification.set_body(msg);
+            Self::DecryptSuccess(device) => {
+                details.device = Some(device);
+                template("decrypt_success", "Device decrypted", "Cleartext device: {device}", "device")
             },
-            Self::DecryptSuccess(msg) => {
-                notification.set_summary("Device decrypted");
-                notification.set_body(&format!("Cleartext device: {}", msg));
+            Self::DecryptFail(reason) => {
+                details.error = Some(reason);
+                template("decrypt_fail", "Failed to decrypt", "{error}", "device.error")
             },
-            Self::DecryptFail(msg) => {
-                notification.set_summary("Failed to decrypt");
-                notification.set_body(msg);
+            Self::UnlockedAndMounted(mount_path) => {
+                details.mount_path = Some(mount_path);
+                template("unlocked_and_mounted", "Device unlocked and mounted", "Mounted at {mount_path}", "device")
+            },
+            Self::Locked(device) => {
+                details.device = Some(device);
+                template("locked", "Device locked", "{device}", "device")
+            },
+            Self::ConfigError(reason) => {
+                details.error = Some(reason);
+                template("config_error", "Config not reloaded", "{error}", "")
             }
-        };
+        }
+    }
+
+    /// Names of the events the notice is published as, notices about
+    /// progress aren't published
+    pub fn events(&self) -> &'static [&'static str] {
+        match self {
+            Self::NewFilesystem(_) | Self::NewEncrypted(_) => &["attached"],
+            Self::MountSuccess(_) => &["mounted"],
+            Self::MountFail(_, _) => &["mount_failed"],
+            Self::MountVetoed(_, _) => &["mount_vetoed"],
+            Self::UnmountSuccess(_) => &["unmounted"],
+            Self::UnsafeRemoval(_, _) => &["unsafe_removal"],
+            Self::EjectFail(_, _) => &["eject_failed"],
+            Self::SafeToRemove(_) => &["ejected"],
+            Self::DecryptSuccess(_) => &["unlocked"],
+            Self::DecryptFail(_) => &["unlock_failed"],
+            Self::UnlockedAndMounted(_) => &["unlocked", "mounted"],
+            Self::Locked(_) => &["locked"],
+            Self::Mounting(_) | Self::Unlocking(_) | Self::WaitingForKey(_, _) | Self::EjectStep(_) | Self::ConfigError(_) => &[]
+        }
+    }
+
+    /// `details` along with anything the notice carries
+    pub fn details(&self, mut details: Details<'a>) -> Details<'a> {
+        self.template(&mut details);
+        details
+    }
+
+    /// Builds the notification from the configured templates, `None` if
+    /// notices of this kind are turned off
+    pub fn render(self, settings: &NotificationSettings, mut details: Details<'a>) -> Option<Notification> {
+        let template = self.template(&mut details);
+        let kind = settings.kind.get(template.kind);
+
+        if kind.and_then(|kind| kind.enabled) == Some(false) {
+            return None;
+        }
+
+        let summary = kind.and_then(|kind| kind.summary.as_deref()).unwrap_or(template.summary);
+        let body = kind.and_then(|kind| kind.body.as_deref()).unwrap_or(template.body);
+        let urgency = kind.and_then(|kind| kind.urgency.as_deref()).and_then(Urgency::from_name)
+            .or(template.urgency)
+            .or_else(|| settings.urgency.as_deref().and_then(Urgency::from_name))
+            .unwrap_or(Urgency::Normal);
+        let icon = kind.and_then(|kind| kind.icon.as_deref())
+            .or(settings.icon.as_deref())
+            .or(details.icon);
+
+        let mut notification = Notification::default();
+
+        notification.set_app_name(settings.app_name.as_deref().unwrap_or("Mounter"));
+        notification.set_expire_timeout(kind.and_then(|kind| kind.timeout).or(settings.timeout).unwrap_or(-1));
+        notification.set_summary(&fill(summary, &details));
+        notification.set_body(&fill(body, &details));
+        notification.set_urgency(urgency);
+
+        if let Some(icon) = icon {
+            notification.set_app_icon(icon);
+        }
+
+        if !template.category.is_empty() {
+            notification.set_category(template.category);
+        }
+
+        Some(notification)
+    }
+}
+
+/// Replaces placeholders such as `{label}` in a template, details that
+/// aren't known are left empty. Details are copied in as they are, so a label
+/// that looks like a placeholder isn't filled in itself
+fn fill(template: &str, details: &Details) -> String {
+    let size = details.size.map(format_size);
+    let values = [
+        ("label", details.label), ("device", details.device), ("mount_path", details.mount_path),
+        ("size", size.as_deref()), ("fstype", details.fstype), ("error", details.error),
+        ("drive", details.drive), ("message", details.message)
+    ];
+
+    let mut text = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let value = rest.find('}').and_then(|end| {
+            values.iter().find(|(name, _)| *name == &rest[1..end]).map(|(_, value)| (end, value.unwrap_or("")))
+        });
+
+        match value {
+            Some((end, value)) => {
+                text.push_str(value);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                text.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    text.push_str(rest);
+    text
+}
+
+/// Formats a size in bytes the way disk tools do, e.g. 16.0 GB
+pub fn format_size(size: u64) -> String {
+    let units = ["bytes", "kB", "MB", "GB", "TB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+
+    while value >= 1000.0 && unit < units.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", size, units[0])
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_is_filled_with_details() {
+        let details = Details { label: Some("BACKUP"), mount_path: Some("/run/media/me/BACKUP"), size: Some(16_000_000_000), ..Details::default() };
+
+        assert_eq!(fill("{label} ({size}) at {mount_path}", &details), "BACKUP (16.0 GB) at /run/media/me/BACKUP");
+    }
+
+    #[test]
+    fn unknown_details_are_left_empty() {
+        assert_eq!(fill("{label}: {error}", &Details { label: Some("BACKUP"), ..Details::default() }), "BACKUP: ");
+    }
+
+    #[test]
+    fn details_arent_filled_in_themselves() {
+        let details = Details { label: Some("{device}"), device: Some("/dev/sdb1"), error: Some("{label}"), ..Details::default() };
+
+        assert_eq!(fill("{label} on {device}: {error}", &details), "{device} on /dev/sdb1: {label}");
+    }
+
+    #[test]
+    fn configured_template_replaces_the_default() {
+        let settings: NotificationSettings = toml::from_str("[kind.mount_success]\nsummary = \"{label} is ready\"").unwrap();
+        let details = Details { label: Some("BACKUP"), ..Details::default() };
+        let notification = Notice::MountSuccess("/run/media/me/BACKUP").render(&settings, details).unwrap();
+
+        assert_eq!(notification.get_summary(), "BACKUP is ready");
+        assert_eq!(notification.get_body(), "/run/media/me/BACKUP");
+    }
+
+    #[test]
+    fn disabled_kind_isnt_shown() {
+        let settings: NotificationSettings = toml::from_str("[kind.mount_success]\nenabled = false").unwrap();
+
+        assert!(Notice::MountSuccess("/run/media/me/BACKUP").render(&settings, Details::default()).is_none());
+    }
 
-        notification
+    #[test]
+    fn sizes_are_shown_in_powers_of_1000() {
+        assert_eq!(format_size(999), "999 bytes");
+        assert_eq!(format_size(1_500), "1.5 kB");
+        assert_eq!(format_size(16_008_609_792), "16.0 GB");
     }
 }
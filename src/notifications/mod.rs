@@ -1,9 +1,16 @@
+use std::collections::HashMap;
 use std::vec::Vec;
 use std::error;
 use std::fmt;
+use std::io;
+use crate::config::SinkSettings;
 mod notification;
 mod dbus_interface;
-pub use notification::Notification;
+mod session;
+mod sinks;
+pub use notification::{Notification, Urgency};
+pub use session::{NotificationEvent, events};
+pub use sinks::NotificationSink;
 
 type Result<T> = std::result::Result<T, NotifierError>;
 
@@ -11,50 +18,260 @@ pub trait Notifiable {
     fn as_notification(self) -> Notification;
 }
 
+impl Notifiable for Notification {
+    fn as_notification(self) -> Notification {
+        self
+    }
+}
+
 #[derive(Debug)]
-pub struct NotifierError(dbus::Error);
+pub enum NotifierError {
+    DBus(dbus::Error),
+    Io(io::Error)
+}
 
 impl fmt::Display for NotifierError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Notifier error: {}", self.0)
+        match self {
+            Self::DBus(e) => write!(f, "Notifier error: {}", e),
+            Self::Io(e) => write!(f, "Notifier error: {}", e)
+        }
     }
 }
 
 impl error::Error for NotifierError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        Some(&self.0)
+        match self {
+            Self::DBus(e) => Some(e),
+            Self::Io(e) => Some(e)
+        }
     }
 }
 
 impl From<dbus::Error> for NotifierError {
     fn from(err: dbus::Error) -> NotifierError {
-        NotifierError(err)
+        NotifierError::DBus(err)
+    }
+}
+
+impl From<io::Error> for NotifierError {
+    fn from(err: io::Error) -> NotifierError {
+        NotifierError::Io(err)
     }
 }
 
-#[allow(dead_code)]
+/// Keeps one notification per group of devices, usually a drive, and
+/// updates it in place as things happen to the devices in it rather than
+/// showing a new popup each time. Notifications are delivered to every
+/// configured sink
+#[derive(Debug)]
 pub struct Notifier {
-    history: Vec<Notification>
+    settings: Vec<SinkSettings>,
+    sinks: Vec<Box<dyn NotificationSink>>,
+    groups: HashMap<String, Group>
+}
+
+#[derive(Debug)]
+struct Group {
+    /// The id each sink gave the group's notification, in the same order as
+    /// the sinks
+    notification_ids: Vec<Option<u32>>,
+    title: String,
+    members: Vec<Member>
+}
+
+#[derive(Debug)]
+struct Member {
+    key: String,
+    label: String,
+    notification: Notification,
+    actions: Vec<(String, String)>
 }
 
 impl Notifier {
-    pub fn notify(notifiable: impl Notifiable) -> Result<Self> {
-        let mut notification = notifiable.as_notification();
-        notification.send()?;
+    /// Creates a notifier delivering to the given sinks, or to desktop
+    /// popups if there are none
+    pub fn new(settings: &[SinkSettings]) -> Self {
+        let mut notifier = Notifier {
+            settings: Vec::new(),
+            sinks: Vec::new(),
+            groups: HashMap::new()
+        };
 
-        Ok(Notifier {
-            history: vec!(notification)
-        })
+        notifier.configure(settings);
+        notifier
     }
 
-    pub fn replace_last(&mut self, replacement: impl Notifiable) -> Result<()> {
-        let mut replacement = replacement.as_notification();
-        if let Some(last_notification_id) = self.history.last().and_then(|ln| ln.get_notification_id()) {
-            replacement.set_replaces_id(last_notification_id);
-            replacement.send()?;
-            self.history.push(replacement);
+    /// Changes the sinks notifications are delivered to. Notifications that
+    /// are already showing can't be updated through new sinks so they're
+    /// left alone, and the next notice for them is shown afresh
+    pub fn configure(&mut self, settings: &[SinkSettings]) {
+        let settings = if settings.is_empty() { vec!(SinkSettings::Freedesktop) } else { settings.to_vec() };
+
+        if settings == self.settings {
+            return;
+        }
+
+        self.sinks = settings.iter().map(sinks::from_settings).collect();
+        self.settings = settings;
+
+        for group in self.groups.values_mut() {
+            group.notification_ids = vec!(None; self.sinks.len());
+        }
+    }
+
+    /// Sends a one off notification that isn't about a particular device
+    pub fn notify(&mut self, notifiable: impl Notifiable) -> Result<()> {
+        let notification = notifiable.as_notification();
+        let mut result = Ok(());
+
+        // Keep going after a failure so one broken sink doesn't hide
+        // notifications from the others
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.send(&notification) {
+                result = result.and(Err(e));
+            }
+        }
+
+        result
+    }
+
+    /// Shows a notice about one member of a group, replacing whatever was
+    /// last shown for that member. `title` names the group and `label` the
+    /// member, they're used when the group has more than one member. Actions
+    /// are pairs of action keys and button labels, they're left off if the
+    /// server can't show buttons
+    pub fn show(&mut self, group: &str, title: &str, member: &str, label: &str, notifiable: impl Notifiable, actions: &[(String, String)]) -> Result<()> {
+        let sink_count = self.sinks.len();
+        let group = self.groups.entry(group.to_owned()).or_insert_with(|| Group {
+            notification_ids: vec!(None; sink_count),
+            title: title.to_owned(),
+            members: Vec::new()
+        });
+
+        let member = Member {
+            key: member.to_owned(),
+            label: label.to_owned(),
+            notification: notifiable.as_notification(),
+            actions: actions.to_vec()
+        };
+
+        match group.members.iter_mut().find(|existing| existing.key == member.key) {
+            Some(existing) => *existing = member,
+            None => group.members.push(member)
+        }
+
+        group.send(&mut self.sinks)
+    }
+
+    /// Replaces everything shown for a group with a single notice, used for
+    /// notices about a whole drive
+    pub fn show_group(&mut self, group: &str, title: &str, notifiable: impl Notifiable) -> Result<()> {
+        if let Some(existing) = self.groups.get_mut(group) {
+            existing.members.clear();
+        }
+
+        self.show(group, title, group, title, notifiable, &[])
+    }
+
+    /// Takes a member out of its group, the notification is closed once the
+    /// group is empty
+    pub fn remove(&mut self, group: &str, member: &str) -> Result<()> {
+        let empty = match self.groups.get_mut(group) {
+            Some(existing) => {
+                let count = existing.members.len();
+                existing.members.retain(|existing| existing.key != member);
+
+                if existing.members.len() == count {
+                    return Ok(());
+                }
+
+                existing.members.is_empty()
+            },
+            None => return Ok(())
+        };
+
+        if empty {
+            self.close(group)
+        } else {
+            let sinks = &mut self.sinks;
+            self.groups.get_mut(group).map(|group| group.send(sinks)).unwrap_or(Ok(()))
+        }
+    }
+
+    /// Closes a group's notification
+    pub fn close(&mut self, group: &str) -> Result<()> {
+        let notification_ids = match self.groups.remove(group) {
+            Some(group) => group.notification_ids,
+            None => return Ok(())
+        };
+
+        let mut result = Ok(());
+
+        for (sink, id) in self.sinks.iter_mut().zip(notification_ids) {
+            if let Some(id) = id {
+                result = result.and(sink.close(id));
+            }
+        }
+
+        result
+    }
+
+    /// Forgets about a notification the user has closed so the next notice
+    /// for its group pops up again
+    pub fn closed(&mut self, id: u32) {
+        self.groups.retain(|_, group| !group.notification_ids.contains(&Some(id)));
+    }
+}
+
+impl Group {
+    /// Delivers the group's notification to every sink, replacing what each
+    /// one showed last. Buttons are always added, sinks that can't show them
+    /// leave them off
+    fn send(&mut self, sinks: &mut [Box<dyn NotificationSink>]) -> Result<()> {
+        let notification = match self.members.as_slice() {
+            [member] => {
+                let mut notification = member.notification.clone();
+
+                for (key, label) in &member.actions {
+                    notification.add_action(key, label);
+                }
+
+                notification
+            },
+            members => {
+                // List every member and label buttons with the member they're for
+                let mut notification = members[members.len() - 1].notification.clone();
+                let body: Vec<String> = members.iter()
+                    .map(|member| format!("{}: {}", member.label, member.notification.get_summary()))
+                    .collect();
+
+                notification.set_summary(&self.title);
+                notification.set_body(&body.join("\n"));
+                notification.set_urgency(members.iter().map(|member| member.notification.get_urgency()).max().unwrap_or(Urgency::Normal));
+
+                for member in members {
+                    for (key, label) in &member.actions {
+                        notification.add_action(key, &format!("{} {}", label, member.label));
+                    }
+                }
+
+                notification
+            }
+        };
+
+        let mut result = Ok(());
+
+        for (sink, id) in sinks.iter_mut().zip(self.notification_ids.iter_mut()) {
+            let mut notification = notification.clone();
+            notification.set_replaces_id(id.unwrap_or(0));
+
+            match sink.send(&notification) {
+                Ok(new_id) => *id = new_id,
+                Err(e) => result = result.and(Err(e))
+            }
         }
 
-        Ok(())
+        result
     }
 }
@@ -1,8 +1,38 @@
-use dbus::blocking::Connection;
 use dbus::arg::Variant;
 use std::time::Duration;
 use std::collections::HashMap;
 use super::dbus_interface::OrgFreedesktopNotifications;
+use super::session;
+
+/// How urgent a notification is, servers may display critical notifications
+/// differently and not expire them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Urgency {
+    Low = 0,
+    Normal = 1,
+    Critical = 2
+}
+
+impl Urgency {
+    /// Parses an urgency as it's written in the config file
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "low" => Some(Urgency::Low),
+            "normal" => Some(Urgency::Normal),
+            "critical" => Some(Urgency::Critical),
+            _ => None
+        }
+    }
+
+    /// Name of the urgency as it's written in the config file
+    pub fn name(self) -> &'static str {
+        match self {
+            Urgency::Low => "low",
+            Urgency::Normal => "normal",
+            Urgency::Critical => "critical"
+        }
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct Notification {
@@ -17,6 +47,23 @@ pub struct Notification {
     expire_timeout: i32,
 }
 
+// Hints hold boxed D-Bus values which can only be cloned through RefArg
+impl Clone for Notification {
+    fn clone(&self) -> Self {
+        Notification {
+            notification_id: self.notification_id,
+            app_name: self.app_name.clone(),
+            replaces_id: self.replaces_id,
+            app_icon: self.app_icon.clone(),
+            summary: self.summary.clone(),
+            body: self.body.clone(),
+            actions: self.actions.clone(),
+            hints: self.hints.iter().map(|(key, value)| (key.clone(), Variant(value.0.box_clone()))).collect(),
+            expire_timeout: self.expire_timeout
+        }
+    }
+}
+
 impl Notification {
     pub fn set_app_name(&mut self, app_name: &str) {
         self.app_name = app_name.to_string();
@@ -38,6 +85,27 @@ impl Notification {
         self.body = body.to_string();
     }
 
+    /// Adds a button to the notification, `key` is reported back when it's
+    /// pressed
+    pub fn add_action(&mut self, key: &str, label: &str) {
+        self.actions.push(key.to_string());
+        self.actions.push(label.to_string());
+    }
+
+    pub fn clear_actions(&mut self) {
+        self.actions.clear();
+    }
+
+    pub fn set_urgency(&mut self, urgency: Urgency) {
+        self.hints.insert("urgency".to_string(), Variant(Box::new(urgency as u8)));
+    }
+
+    /// Sets the category hint, e.g. `device.added`, which servers can use to
+    /// group or style notifications
+    pub fn set_category(&mut self, category: &str) {
+        self.hints.insert("category".to_string(), Variant(Box::new(category.to_string())));
+    }
+
     pub fn set_expire_timeout(&mut self, expire_timeout: i32) {
         self.expire_timeout = expire_timeout;
     }
@@ -66,31 +134,53 @@ impl Notification {
         &self.body
     }
 
+    pub fn get_urgency(&self) -> Urgency {
+        match self.hints.get("urgency").and_then(|urgency| urgency.0.as_u64()) {
+            Some(0) => Urgency::Low,
+            Some(2) => Urgency::Critical,
+            _ => Urgency::Normal
+        }
+    }
+
+    pub fn get_category(&self) -> Option<&str> {
+        self.hints.get("category").and_then(|category| category.0.as_str())
+    }
+
     pub fn get_expire_timeout(&mut self, expire_timeout: i32) {
         self.expire_timeout = expire_timeout;
     }
 
-    pub fn send(&mut self) -> Result<(), dbus::Error> {
-        let conn = Connection::new_session().expect("Could not connect to session bus");
-        let proxy = conn.with_proxy(
-            "org.freedesktop.Notifications",
-            "/org/freedesktop/Notifications",
-            Duration::from_millis(5000)
-        );
-
-        proxy.notify(
-            &self.app_name,
-            self.replaces_id,
-            &self.app_icon,
-            &self.summary,
-            &self.body,
-            self.actions.iter().map(AsRef::as_ref).collect(),
-            HashMap::new(),
-            self.expire_timeout
-        ).and_then(move |id| {
-            self.notification_id = Some(id);
-            Ok(())
+    /// Closes a notification that was sent earlier
+    pub fn close(id: u32) -> Result<(), dbus::Error> {
+        session::with_session(|conn| {
+            conn.with_proxy("org.freedesktop.Notifications", "/org/freedesktop/Notifications", Duration::from_millis(5000))
+                .close_notification(id)
         })
     }
+
+    pub fn send(&mut self) -> Result<(), dbus::Error> {
+        let id = session::with_session(|conn| {
+            let proxy = conn.with_proxy(
+                "org.freedesktop.Notifications",
+                "/org/freedesktop/Notifications",
+                Duration::from_millis(5000)
+            );
+
+            proxy.notify(
+                &self.app_name,
+                self.replaces_id,
+                &self.app_icon,
+                &self.summary,
+                &self.body,
+                self.actions.iter().map(AsRef::as_ref).collect(),
+                self.hints.iter().map(|(key, value)| (key.as_str(), Variant(value.0.box_clone()))).collect(),
+                self.expire_timeout
+            )
+        })?;
+
+        self.notification_id = Some(id);
+
+        Ok(())
+    }
 }
 
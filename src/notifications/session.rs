@@ -0,0 +1,108 @@
+use dbus::blocking::Connection;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::Duration;
+use super::dbus_interface::{
+    OrgFreedesktopNotifications,
+    OrgFreedesktopNotificationsActionInvoked,
+    OrgFreedesktopNotificationsNotificationClosed
+};
+
+/// Something the user did with a notification
+#[derive(Debug)]
+pub enum NotificationEvent {
+    /// A notification's button was pressed, with the key of its action
+    ActionInvoked(String),
+    /// A notification was dismissed or expired
+    Closed(u32)
+}
+
+// Notifications are sent from a single connection that stays open, some
+// servers only send action signals to the connection that sent the
+// notification
+struct Session {
+    conn: RefCell<Option<Connection>>,
+    events: Rc<RefCell<Vec<NotificationEvent>>>,
+    supports_actions: Cell<Option<bool>>
+}
+
+thread_local! {
+    static SESSION: Session = Session {
+        conn: RefCell::new(None),
+        events: Rc::new(RefCell::new(Vec::new())),
+        supports_actions: Cell::new(None)
+    };
+}
+
+/// Runs `f` with the session bus connection, connecting first if needed
+pub fn with_session<T, F>(f: F) -> Result<T, dbus::Error>
+    where F: FnOnce(&Connection) -> Result<T, dbus::Error>
+{
+    SESSION.with(|session| {
+        let mut session = session.conn.borrow_mut();
+        let conn = match session.take() {
+            Some(conn) => conn,
+            None => connect()?
+        };
+
+        let result = f(&conn);
+        *session = Some(conn);
+
+        result
+    })
+}
+
+fn connect() -> Result<Connection, dbus::Error> {
+    let conn = Connection::new_session()?;
+    let proxy = conn.with_proxy("org.freedesktop.Notifications", "/org/freedesktop/Notifications", Duration::from_millis(5000));
+    let events = SESSION.with(|session| Rc::clone(&session.events));
+
+    proxy.match_signal(move |signal: OrgFreedesktopNotificationsActionInvoked, _conn: &Connection| {
+        events.borrow_mut().push(NotificationEvent::ActionInvoked(signal.action_key));
+        true
+    })?;
+
+    let events = SESSION.with(|session| Rc::clone(&session.events));
+
+    proxy.match_signal(move |signal: OrgFreedesktopNotificationsNotificationClosed, _conn: &Connection| {
+        events.borrow_mut().push(NotificationEvent::Closed(signal.id));
+        true
+    })?;
+
+    Ok(conn)
+}
+
+/// Whether the notification server can show buttons, remembered once a
+/// server has answered
+pub fn supports_actions() -> bool {
+    if let Some(supported) = SESSION.with(|session| session.supports_actions.get()) {
+        return supported;
+    }
+
+    let capabilities = with_session(|conn| {
+        conn.with_proxy("org.freedesktop.Notifications", "/org/freedesktop/Notifications", Duration::from_millis(5000))
+            .get_capabilities()
+    });
+
+    match capabilities {
+        Ok(capabilities) => {
+            let supported = capabilities.iter().any(|capability| capability == "actions");
+            SESSION.with(|session| session.supports_actions.set(Some(supported)));
+            supported
+        },
+        // Ask again once a server is running
+        Err(_) => false
+    }
+}
+
+/// Notification events received since this was last called. Doesn't block,
+/// and does nothing if no notification has been sent yet
+pub fn events() -> Vec<NotificationEvent> {
+    SESSION.with(|session| {
+        if let Some(conn) = session.conn.borrow_mut().as_mut() {
+            while let Ok(true) = conn.process(Duration::from_millis(0)) {}
+        }
+
+        session.events.borrow_mut().drain(..).collect()
+    })
+}
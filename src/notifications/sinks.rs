@@ -0,0 +1,170 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use serde::Serialize;
+use crate::config::SinkSettings;
+use crate::incidents;
+use super::{Notification, Result, session};
+
+/// Somewhere notifications are delivered to
+pub trait NotificationSink: fmt::Debug {
+    /// Delivers a notification, returning the id it was given if the sink
+    /// can replace or close it later
+    fn send(&mut self, notification: &Notification) -> Result<Option<u32>>;
+
+    /// Closes a notification that was delivered earlier
+    fn close(&mut self, _id: u32) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Creates the sink for its settings
+pub fn from_settings(settings: &SinkSettings) -> Box<dyn NotificationSink> {
+    match settings {
+        SinkSettings::Freedesktop => Box::new(FreedesktopSink::default()),
+        SinkSettings::Log => Box::new(LogSink),
+        SinkSettings::File { path } => Box::new(FileSink { path: path.to_owned() }),
+        SinkSettings::Command { command } => Box::new(CommandSink { command: command.to_owned() })
+    }
+}
+
+/// Desktop popups shown by a freedesktop notification server over the
+/// session bus. If there's no session bus or nothing is serving
+/// notifications they're written to stderr instead, and the server is tried
+/// again for the next notification
+#[derive(Debug, Default)]
+pub struct FreedesktopSink {
+    unavailable: bool
+}
+
+impl NotificationSink for FreedesktopSink {
+    fn send(&mut self, notification: &Notification) -> Result<Option<u32>> {
+        let mut notification = notification.clone();
+
+        if !session::supports_actions() {
+            notification.clear_actions();
+        }
+
+        match notification.send() {
+            Ok(()) => {
+                if self.unavailable {
+                    eprintln!("Notification server is available again");
+                    self.unavailable = false;
+                }
+
+                Ok(notification.get_notification_id())
+            },
+            Err(e) => {
+                // Only mention it once rather than for every notification
+                if !self.unavailable {
+                    eprintln!("Could not show notification, writing notifications to stderr instead: {}", e);
+                    self.unavailable = true;
+                }
+
+                LogSink.send(&notification)
+            }
+        }
+    }
+
+    fn close(&mut self, id: u32) -> Result<()> {
+        Notification::close(id)?;
+
+        Ok(())
+    }
+}
+
+/// Writes notifications to stderr, which ends up in the journal when udman
+/// runs as a systemd user service
+#[derive(Debug)]
+pub struct LogSink;
+
+impl NotificationSink for LogSink {
+    fn send(&mut self, notification: &Notification) -> Result<Option<u32>> {
+        let body = notification.get_body().replace('\n', "; ");
+
+        if body.is_empty() {
+            eprintln!("[{}] {}", notification.get_urgency().name(), notification.get_summary());
+        } else {
+            eprintln!("[{}] {}: {}", notification.get_urgency().name(), notification.get_summary(), body);
+        }
+
+        Ok(None)
+    }
+}
+
+/// Appends each notification to a file as a line of JSON
+#[derive(Debug)]
+pub struct FileSink {
+    path: PathBuf
+}
+
+impl NotificationSink for FileSink {
+    fn send(&mut self, notification: &Notification) -> Result<Option<u32>> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let line = serde_json::to_string(&Record::new(notification)).unwrap_or_default();
+
+        writeln!(file, "{}", line)?;
+
+        Ok(None)
+    }
+}
+
+/// Runs a command for each notification with the notification in `UDMAN_*`
+/// environment variables. The command isn't waited on so a slow one can't
+/// hold up udman
+#[derive(Debug)]
+pub struct CommandSink {
+    command: String
+}
+
+impl NotificationSink for CommandSink {
+    fn send(&mut self, notification: &Notification) -> Result<Option<u32>> {
+        let mut child = Command::new(&self.command)
+            .env("UDMAN_APP_NAME", notification.get_app_name())
+            .env("UDMAN_SUMMARY", notification.get_summary())
+            .env("UDMAN_BODY", notification.get_body())
+            .env("UDMAN_URGENCY", notification.get_urgency().name())
+            .env("UDMAN_ICON", notification.get_app_icon())
+            .env("UDMAN_CATEGORY", notification.get_category().unwrap_or(""))
+            .stdin(Stdio::null())
+            .spawn()?;
+
+        // Reap the command once it exits
+        thread::spawn(move || child.wait().ok());
+
+        Ok(None)
+    }
+}
+
+/// A notification as it's written to a JSONL file
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    app_name: &'a str,
+    summary: &'a str,
+    body: &'a str,
+    urgency: &'static str,
+    icon: &'a str,
+    category: Option<&'a str>
+}
+
+impl<'a> Record<'a> {
+    fn new(notification: &'a Notification) -> Self {
+        Record {
+            time: incidents::utc_timestamp(),
+            app_name: notification.get_app_name(),
+            summary: notification.get_summary(),
+            body: notification.get_body(),
+            urgency: notification.get_urgency().name(),
+            icon: notification.get_app_icon(),
+            category: notification.get_category()
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// A process that has files open under a path
+#[derive(Clone, Debug, PartialEq)]
+pub struct Process {
+    pub pid: u32,
+    pub name: String
+}
+
+impl fmt::Display for Process {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.name, self.pid)
+    }
+}
+
+/// Finds processes that are keeping a mount point busy, either through an
+/// open file, their working directory or a mapped file such as a library.
+/// Processes belonging to other users can't be inspected without privileges
+/// and are skipped
+pub fn using_path(mount_path: &Path) -> Vec<Process> {
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new()
+    };
+
+    let mut processes: Vec<Process> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()))
+        .filter(|pid| uses_path(*pid, mount_path))
+        .map(|pid| Process {
+            pid,
+            name: fs::read_to_string(format!("/proc/{}/comm", pid))
+                .map(|name| name.trim_end().to_owned())
+                .unwrap_or_else(|_| "unknown".to_owned())
+        })
+        .collect();
+
+    processes.sort_by_key(|process| process.pid);
+    processes
+}
+
+fn uses_path(pid: u32, mount_path: &Path) -> bool {
+    let proc_dir = format!("/proc/{}", pid);
+    let is_under = |path: &Path| path.starts_with(mount_path);
+
+    if fs::read_link(format!("{}/cwd", proc_dir)).map(|cwd| is_under(&cwd)).unwrap_or(false) {
+        return true;
+    }
+
+    if let Ok(fds) = fs::read_dir(format!("{}/fd", proc_dir)) {
+        let open_file = fds
+            .filter_map(|fd| fd.ok())
+            .filter_map(|fd| fs::read_link(fd.path()).ok())
+            .any(|target| is_under(&target));
+
+        if open_file {
+            return true;
+        }
+    }
+
+    // The path is the last column of a mapping and none of the columns
+    // before it contain a slash
+    fs::read_to_string(format!("{}/maps", proc_dir)).map(|maps| {
+        maps.lines()
+            .filter_map(|line| line.find('/').map(|start| &line[start..]))
+            .any(|path| is_under(Path::new(path)))
+    }).unwrap_or(false)
+}
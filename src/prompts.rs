@@ -0,0 +1,456 @@
+use std::env;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use dialog::DialogBox;
+use crate::config::PromptSettings;
+use crate::notices;
+
+/// How many times the user is asked for a passphrase if the config doesn't
+/// say
+pub const DEFAULT_ATTEMPTS: u32 = 3;
+
+const TITLE: &str = "Unlock encrypted device";
+
+/// Asks the user for the passphrase of an encrypted device
+pub trait PasswordPrompter: fmt::Debug + Send {
+    /// Asks for a passphrase, returning `None` if the user cancelled. The
+    /// prompt is closed if `cancelled` is set while it's waiting
+    fn prompt(&self, request: &PromptRequest, cancelled: &AtomicBool) -> Result<Option<Answer>, String>;
+}
+
+/// What the user entered
+#[derive(Debug)]
+pub struct Answer {
+    /// The passphrase as it was typed
+    pub passphrase: String,
+    /// Whether the passphrase should be saved in the keyring once it's
+    /// unlocked the device
+    pub remember: bool
+}
+
+/// Creates the prompter for its settings
+pub fn from_settings(settings: &PromptSettings) -> Box<dyn PasswordPrompter> {
+    match settings {
+        PromptSettings::Dialog => Box::new(DialogPrompter),
+        PromptSettings::Pinentry { program } => Box::new(PinentryPrompter { program: program.to_owned() }),
+        PromptSettings::Systemd => Box::new(SystemdPrompter),
+        PromptSettings::Askpass { program } => Box::new(AskpassPrompter { program: program.to_owned() }),
+        PromptSettings::Tty => Box::new(TtyPrompter)
+    }
+}
+
+/// The device a passphrase is wanted for, so the user knows what's asking
+#[derive(Clone, Debug, Default)]
+pub struct PromptRequest {
+    /// Label of the device, or its device file if it has no label
+    pub label: String,
+    /// Device file e.g. `/dev/sdb1`
+    pub device: String,
+    /// Size in bytes
+    pub size: Option<u64>,
+    /// Vendor and model of the drive the device is on
+    pub drive: Option<String>,
+    /// Which attempt this is, starting from 1
+    pub attempt: u32,
+    /// How many attempts there are
+    pub attempts: u32,
+    /// Why the last attempt failed
+    pub error: Option<String>,
+    /// Whether the user can choose to have the passphrase remembered in the
+    /// keyring
+    pub remember: bool
+}
+
+impl PromptRequest {
+    /// Which device is asking, e.g. "BACKUP (16.0 GB, /dev/sdb1) on
+    /// SanDisk Cruzer"
+    fn subject(&self) -> String {
+        let mut subject = match self.size {
+            Some(size) => format!("{} ({}, {})", self.label, notices::format_size(size), self.device),
+            None => format!("{} ({})", self.label, self.device)
+        };
+
+        if let Some(drive) = &self.drive {
+            subject.push_str(&format!(" on {}", drive));
+        }
+
+        subject
+    }
+
+    fn description(&self) -> String {
+        format!("Enter the passphrase to unlock {}", self.subject())
+    }
+
+    /// Why the last attempt failed and how many are left
+    fn retry(&self) -> Option<String> {
+        let error = self.error.as_ref()?;
+
+        Some(format!("{}, attempt {} of {}", error, self.attempt, self.attempts))
+    }
+
+    fn remember_question(&self) -> String {
+        format!("Remember the passphrase for {} in the keyring?", self.label)
+    }
+
+    /// The description followed by the retry message, for prompts that only
+    /// show one piece of text
+    fn text(&self) -> String {
+        match self.retry() {
+            Some(retry) => format!("{}\n\n{}", self.description(), retry),
+            None => self.description()
+        }
+    }
+}
+
+/// A dialog box from the `dialog` crate. It blocks until it's answered so
+/// it's left open if the prompt is cancelled, the answer is thrown away.
+/// Whether to remember the passphrase is asked in a second dialog box
+#[derive(Debug)]
+pub struct DialogPrompter;
+
+impl PasswordPrompter for DialogPrompter {
+    fn prompt(&self, request: &PromptRequest, _cancelled: &AtomicBool) -> Result<Option<Answer>, String> {
+        let passphrase = dialog::Password::new(request.text())
+            .title(TITLE)
+            .show()
+            .map_err(|e| e.to_string())?;
+
+        let passphrase = match passphrase {
+            Some(passphrase) => passphrase,
+            None => return Ok(None)
+        };
+
+        let remember = request.remember && dialog::Question::new(request.remember_question())
+            .title(TITLE)
+            .show()
+            .map_err(|e| e.to_string())? == dialog::Choice::Yes;
+
+        Ok(Some(Answer { passphrase, remember }))
+    }
+}
+
+/// A pinentry program such as `pinentry-gnome3` or `pinentry-curses`.
+/// Whether to remember the passphrase is asked in the same window once it's
+/// entered
+#[derive(Debug)]
+pub struct PinentryPrompter {
+    program: Option<String>
+}
+
+impl PasswordPrompter for PinentryPrompter {
+    fn prompt(&self, request: &PromptRequest, cancelled: &AtomicBool) -> Result<Option<Answer>, String> {
+        let mut commands = vec![
+            format!("SETTITLE {}", assuan_escape(TITLE)),
+            format!("SETDESC {}", assuan_escape(&request.description())),
+            "SETPROMPT Passphrase:".to_owned()
+        ];
+
+        if let Some(retry) = request.retry() {
+            commands.push(format!("SETERROR {}", assuan_escape(&retry)));
+        }
+
+        let remember = if request.remember {
+            vec![
+                format!("SETDESC {}", assuan_escape(&request.remember_question())),
+                "SETOK Remember".to_owned(),
+                "SETCANCEL Don't remember".to_owned()
+            ]
+        } else {
+            Vec::new()
+        };
+
+        let command = Command::new(self.program.as_deref().unwrap_or("pinentry"));
+        let (answer, _) = converse(command, cancelled, move |stdin, stdout| get_pin(stdin, stdout, &commands, &remember))?;
+
+        Ok(answer)
+    }
+}
+
+/// `systemd-ask-password`, which is answered by the password agent of the
+/// desktop or `systemd-tty-ask-password-agent`. Password agents can only
+/// answer with a passphrase so it's never remembered
+#[derive(Debug)]
+pub struct SystemdPrompter;
+
+impl PasswordPrompter for SystemdPrompter {
+    fn prompt(&self, request: &PromptRequest, cancelled: &AtomicBool) -> Result<Option<Answer>, String> {
+        let mut command = Command::new("systemd-ask-password");
+        command.arg("--timeout=0")
+            .arg("--icon=drive-harddisk")
+            .arg(format!("--id=udman:{}", request.device))
+            .arg(request.text().replace("\n\n", ". "));
+
+        let (output, status) = converse(command, cancelled, |_, stdout| read_all(stdout))?;
+
+        // It exits with an error if the request was cancelled
+        Ok(Some(Answer { passphrase: chomp(&output), remember: false }).filter(|_| status.success()))
+    }
+}
+
+/// A program in the style of `SSH_ASKPASS`, it's given the prompt as its
+/// only argument and prints the passphrase. Whether to remember the
+/// passphrase is asked by running it again with `SSH_ASKPASS_PROMPT=confirm`,
+/// which asks a yes or no question in the programs that understand it
+#[derive(Debug)]
+pub struct AskpassPrompter {
+    program: Option<String>
+}
+
+impl PasswordPrompter for AskpassPrompter {
+    fn prompt(&self, request: &PromptRequest, cancelled: &AtomicBool) -> Result<Option<Answer>, String> {
+        let program = self.program.clone()
+            .or_else(|| env::var("SSH_ASKPASS").ok())
+            .ok_or_else(|| "No askpass program given and SSH_ASKPASS isn't set".to_owned())?;
+
+        let mut command = Command::new(&program);
+        command.arg(request.text());
+
+        let (output, status) = converse(command, cancelled, |_, stdout| read_all(stdout))?;
+
+        // Cancelling exits with an error
+        if !status.success() {
+            return Ok(None);
+        }
+
+        let remember = request.remember && {
+            let mut command = Command::new(&program);
+            command.env("SSH_ASKPASS_PROMPT", "confirm").arg(request.remember_question());
+
+            converse(command, cancelled, |_, stdout| read_all(stdout))?.1.success()
+        };
+
+        Ok(Some(Answer { passphrase: chomp(&output), remember }))
+    }
+}
+
+/// Asks on the terminal udman was started from
+#[derive(Debug)]
+pub struct TtyPrompter;
+
+impl PasswordPrompter for TtyPrompter {
+    fn prompt(&self, request: &PromptRequest, cancelled: &AtomicBool) -> Result<Option<Answer>, String> {
+        let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty").map_err(|e| format!("Couldn't open terminal: {}", e))?;
+
+        writeln!(tty, "{}", request.text()).and_then(|_| write!(tty, "Passphrase: ")).map_err(|e| e.to_string())?;
+
+        let hidden = set_echo(false);
+        let passphrase = read_line(&tty, cancelled);
+
+        if hidden {
+            set_echo(true);
+        }
+
+        writeln!(tty).ok();
+
+        let passphrase = match passphrase? {
+            Some(passphrase) => passphrase,
+            None => return Ok(None)
+        };
+
+        let remember = request.remember && {
+            write!(tty, "{} [y/N] ", request.remember_question()).map_err(|e| e.to_string())?;
+
+            let reply = read_line(&tty, cancelled)?.unwrap_or_default();
+            matches!(reply.trim(), "y" | "Y" | "yes")
+        };
+
+        Ok(Some(Answer { passphrase, remember }))
+    }
+}
+
+/// Reads a line from the terminal, `None` if the prompt is cancelled first
+fn read_line(tty: &File, cancelled: &AtomicBool) -> Result<Option<String>, String> {
+    let reader = tty.try_clone().map_err(|e| e.to_string())?;
+    let (sender, answer) = mpsc::channel();
+
+    // Reading can't be interrupted, if the prompt is cancelled the line
+    // that's eventually entered is thrown away with the thread
+    thread::spawn(move || {
+        let mut line = String::new();
+        let read = BufReader::new(reader).read_line(&mut line).map(|_| line);
+        sender.send(read).ok();
+    });
+
+    loop {
+        match answer.recv_timeout(Duration::from_millis(100)) {
+            Ok(Ok(line)) => return Ok(Some(chomp(line.as_bytes()))),
+            Ok(Err(e)) => return Err(e.to_string()),
+            Err(RecvTimeoutError::Timeout) if !cancelled.load(Ordering::Relaxed) => (),
+            Err(_) => return Ok(None)
+        }
+    }
+}
+
+/// Runs a prompt program, talking to it with `talk` on another thread so
+/// the program can be killed if the prompt is cancelled. Returns what `talk`
+/// returned along with how the program exited
+fn converse<T, F>(mut command: Command, cancelled: &AtomicBool, talk: F) -> Result<(T, ExitStatus), String>
+    where T: Send + 'static, F: FnOnce(ChildStdin, BufReader<ChildStdout>) -> io::Result<T> + Send + 'static
+{
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Couldn't run {}: {}", program, e))?;
+
+    let (sender, answer) = mpsc::channel();
+
+    match (child.stdin.take(), child.stdout.take()) {
+        (Some(stdin), Some(stdout)) => {
+            thread::spawn(move || sender.send(talk(stdin, BufReader::new(stdout))).ok());
+        },
+        _ => return Err(format!("Couldn't talk to {}", program))
+    }
+
+    let result = loop {
+        match answer.recv_timeout(Duration::from_millis(100)) {
+            Ok(result) => break result,
+            Err(RecvTimeoutError::Timeout) if !cancelled.load(Ordering::Relaxed) => (),
+            Err(_) => {
+                child.kill().ok();
+                child.wait().ok();
+                return Err("cancelled".to_owned());
+            }
+        }
+    };
+
+    let status = child.wait().map_err(|e| e.to_string())?;
+
+    result.map(|value| (value, status)).map_err(|e| format!("{} failed: {}", program, e))
+}
+
+/// Asks pinentry for a PIN after sending it `commands` to set up the
+/// prompt, `None` if the user cancelled. If there are `remember` commands
+/// they set up a confirmation asked next, whether to remember the PIN
+fn get_pin(mut stdin: ChildStdin, mut stdout: BufReader<ChildStdout>, commands: &[String], remember: &[String]) -> io::Result<Option<Answer>> {
+    // Pinentry greets with an OK once it's ready
+    read_reply(&mut stdout)?;
+
+    for command in commands {
+        writeln!(stdin, "{}", command)?;
+        read_reply(&mut stdout)?;
+    }
+
+    writeln!(stdin, "GETPIN")?;
+
+    let passphrase = match read_reply(&mut stdout) {
+        Ok(pin) => pin.unwrap_or_default(),
+        // Cancelling is reported as an error
+        Err(e) if e.kind() == io::ErrorKind::Other => {
+            writeln!(stdin, "BYE").ok();
+            return Ok(None);
+        },
+        Err(e) => return Err(e)
+    };
+
+    let remember = !remember.is_empty() && confirm(&mut stdin, &mut stdout, remember)?;
+
+    writeln!(stdin, "BYE").ok();
+
+    Ok(Some(Answer { passphrase, remember }))
+}
+
+/// Asks pinentry a yes or no question set up by `commands`
+fn confirm(stdin: &mut ChildStdin, stdout: &mut BufReader<ChildStdout>, commands: &[String]) -> io::Result<bool> {
+    for command in commands {
+        writeln!(stdin, "{}", command)?;
+        read_reply(stdout)?;
+    }
+
+    writeln!(stdin, "CONFIRM")?;
+
+    match read_reply(stdout) {
+        Ok(_) => Ok(true),
+        // Saying no is reported as an error
+        Err(e) if e.kind() == io::ErrorKind::Other => Ok(false),
+        Err(e) => Err(e)
+    }
+}
+
+/// Reads lines up to an `OK`, returning the data sent before it. An `ERR`
+/// is returned as an error of kind `Other`
+fn read_reply(stdout: &mut BufReader<ChildStdout>) -> io::Result<Option<String>> {
+    let mut data: Option<String> = None;
+
+    loop {
+        let mut line = String::new();
+
+        if stdout.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "pinentry exited"));
+        }
+
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+
+        if line == "OK" || line.starts_with("OK ") {
+            return Ok(data);
+        } else if let Some(error) = line.strip_prefix("ERR ") {
+            return Err(io::Error::other(error.to_owned()));
+        } else if let Some(chunk) = line.strip_prefix("D ") {
+            data.get_or_insert_with(String::new).push_str(&assuan_unescape(chunk));
+        }
+
+        // Comments and status lines don't matter here
+    }
+}
+
+/// Percent-escapes the characters Assuan can't have in a line
+fn assuan_escape(text: &str) -> String {
+    text.replace('%', "%25").replace('\n', "%0A").replace('\r', "%0D")
+}
+
+fn assuan_unescape(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let escaped = bytes.get(index + 1..index + 3)
+            .filter(|_| bytes[index] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                unescaped.push(byte);
+                index += 3;
+            },
+            None => {
+                unescaped.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+fn read_all(mut stdout: BufReader<ChildStdout>) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    stdout.read_to_end(&mut output)?;
+
+    Ok(output)
+}
+
+/// Drops the line ending a program or the terminal puts after the
+/// passphrase, anything else is part of it
+fn chomp(output: &[u8]) -> String {
+    let output = String::from_utf8_lossy(output);
+    let output = output.strip_suffix('\n').unwrap_or(&output);
+
+    output.strip_suffix('\r').unwrap_or(output).to_owned()
+}
+
+/// Turns echo on the terminal on or off, returns false if it couldn't
+fn set_echo(echo: bool) -> bool {
+    File::open("/dev/tty")
+        .and_then(|tty| Command::new("stty").arg(if echo { "echo" } else { "-echo" }).stdin(tty).stderr(Stdio::null()).status())
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
@@ -0,0 +1,343 @@
+use dbus::arg::{RefArg, Variant};
+use dbus::blocking::Connection;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::{MatchRule, MessageType};
+use dbus::strings::{Interface, Member, Path};
+use dbus::tree::{Factory, MethodErr};
+use dbus::Message;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+use crate::manager::{self, Manager, Reply};
+use crate::udisks2::devices::{Block, Drive};
+
+/// Name udman owns on the session bus
+pub const BUS_NAME: &str = "org.udman.Manager1";
+/// Object the methods are served from
+pub const OBJECT_PATH: &str = "/org/udman/Manager1";
+/// Interface of the methods and signals
+pub const INTERFACE: &str = "org.udman.Manager1";
+
+type DeviceProperties = HashMap<&'static str, Variant<Box<dyn RefArg>>>;
+
+/// The parts of a published event that signals are made from
+#[derive(Deserialize)]
+struct PublishedEvent {
+    event: String,
+    object_path: Option<String>,
+    device: Option<String>,
+    mount_path: Option<String>
+}
+
+/// A method call that's answered once what it asks for is done, as acting
+/// on a device can take a while
+enum Request {
+    Mount { device: String },
+    Unmount { device: String },
+    Unlock { device: String, passphrase: Option<String>, remember: bool },
+    Lock { device: String },
+    Eject { drive: String, lazy: bool }
+}
+
+type Replies = Rc<RefCell<Vec<Message>>>;
+
+impl Request {
+    /// Hands the request to `manager`, the answer to `call` is added to
+    /// `replies` once it's done
+    fn start(self, call: Message, manager: &mut Manager, replies: &Replies) {
+        match self {
+            Self::Mount { device } => {
+                manager.mount(&device, reply(call, replies, |ret, mount_path: String| ret.append1(mount_path)));
+            },
+            Self::Unmount { device } => {
+                manager.unmount(&device, reply(call, replies, |ret, ()| ret));
+            },
+            Self::Unlock { device, passphrase, remember } => {
+                let reply = reply(call, replies, |ret, cleartext_device: String| ret.append1(Path::from(cleartext_device)));
+                manager.unlock_device(&device, passphrase, remember, reply);
+            },
+            Self::Lock { device } => {
+                manager.lock_device(&device, reply(call, replies, |ret, ()| ret));
+            },
+            Self::Eject { drive, lazy } => {
+                manager.eject(&drive, lazy, Some(reply(call, replies, |ret, ()| ret)));
+            }
+        }
+    }
+}
+
+/// Answers `call` with what `append` adds to the method return, or with the
+/// error if the request failed
+fn reply<T: 'static>(call: Message, replies: &Replies, append: fn(Message, T) -> Message) -> Reply<T> {
+    let replies = Rc::clone(replies);
+
+    Reply::new(move |result| {
+        let message = match result {
+            Ok(value) => append(call.method_return(), value),
+            Err(e) => MethodErr::failed(&e).to_message(&call)
+        };
+
+        replies.borrow_mut().push(message);
+    })
+}
+
+/// Lets other programs on the desktop list devices and ask udman to mount,
+/// unlock and eject them over the session bus. Methods act on the devices
+/// the `Manager` is tracking and events it publishes are sent out as signals
+pub struct Service {
+    conn: Connection,
+    events: Receiver<String>,
+    replies: Replies
+}
+
+impl Service {
+    /// Connects to the session bus and claims `org.udman.Manager1`, fails if
+    /// there's no session bus or another udman already owns the name
+    pub fn start(manager: Rc<RefCell<Manager>>) -> Result<Self, dbus::Error> {
+        let conn = Connection::new_session()?;
+        let reply = conn.request_name(BUS_NAME, false, false, true)?;
+
+        if reply != dbus::blocking::stdintf::org_freedesktop_dbus::RequestNameReply::PrimaryOwner {
+            return Err(dbus::Error::new_custom("org.udman.Error.NameTaken", &format!("{} is already owned by another udman", BUS_NAME)));
+        }
+
+        let events = manager.borrow_mut().subscribe();
+        let f = Factory::new_fn::<()>();
+
+        // Set by the methods that are answered later, the call is needed to
+        // answer it so the request is started once the tree has handled it
+        let request: Rc<RefCell<Option<Request>>> = Rc::new(RefCell::new(None));
+        let replies: Replies = Rc::new(RefCell::new(Vec::new()));
+
+        let list_manager = Rc::clone(&manager);
+        let reload_manager = Rc::clone(&manager);
+        let mount_request = Rc::clone(&request);
+        let unmount_request = Rc::clone(&request);
+        let unlock_request = Rc::clone(&request);
+        let lock_request = Rc::clone(&request);
+        let eject_request = Rc::clone(&request);
+
+        let interface = f.interface(INTERFACE, ())
+            .add_m(f.method("ListDevices", (), move |m| {
+                let devices: Vec<DeviceProperties> = list_manager.borrow().devices()
+                    .into_iter()
+                    .map(|(device, drive)| DeviceStatus::new(device, drive).to_properties())
+                    .collect();
+
+                Ok(vec!(m.msg.method_return().append1(devices)))
+            }).out_arg(("devices", "aa{sv}")))
+            .add_m(f.method("Mount", (), move |m| {
+                let device: &str = m.msg.read1()?;
+                mount_request.replace(Some(Request::Mount { device: device.to_owned() }));
+
+                Ok(vec!())
+            }).inarg::<&str, _>("device").outarg::<&str, _>("mount_path"))
+            .add_m(f.method("Unmount", (), move |m| {
+                let device: &str = m.msg.read1()?;
+                unmount_request.replace(Some(Request::Unmount { device: device.to_owned() }));
+
+                Ok(vec!())
+            }).inarg::<&str, _>("device"))
+            .add_m(f.method("Unlock", (), move |m| {
+                let (device, passphrase, remember): (&str, &str, bool) = m.msg.read3()?;
+                let passphrase = Some(passphrase.to_owned()).filter(|passphrase| !passphrase.is_empty());
+                unlock_request.replace(Some(Request::Unlock { device: device.to_owned(), passphrase, remember }));
+
+                Ok(vec!())
+            }).inarg::<&str, _>("device").inarg::<&str, _>("passphrase").inarg::<bool, _>("remember").outarg::<Path, _>("cleartext_device"))
+            .add_m(f.method("Lock", (), move |m| {
+                let device: &str = m.msg.read1()?;
+                lock_request.replace(Some(Request::Lock { device: device.to_owned() }));
+
+                Ok(vec!())
+            }).inarg::<&str, _>("device"))
+            .add_m(f.method("Eject", (), move |m| {
+                let (drive, lazy): (&str, bool) = m.msg.read2()?;
+                eject_request.replace(Some(Request::Eject { drive: drive.to_owned(), lazy }));
+
+                Ok(vec!())
+            }).inarg::<&str, _>("drive").inarg::<bool, _>("lazy"))
+            .add_m(f.method("ReloadConfig", (), move |m| {
+                reload_manager.borrow_mut().reload().map_err(|e| MethodErr::failed(&e))?;
+
+                Ok(vec!(m.msg.method_return()))
+            }))
+            .add_s(f.signal("DeviceAdded", ()).sarg::<Path, _>("object_path").sarg::<&str, _>("device"))
+            .add_s(f.signal("DeviceMounted", ()).sarg::<Path, _>("object_path").sarg::<&str, _>("device").sarg::<&str, _>("mount_path"))
+            .add_s(f.signal("DeviceRemoved", ()).sarg::<Path, _>("object_path").sarg::<&str, _>("device"));
+
+        let tree = f.tree(())
+            .add(f.object_path(OBJECT_PATH, ()).introspectable().add(interface));
+
+        let mut rule = MatchRule::new();
+        rule.msg_type = Some(MessageType::MethodCall);
+
+        let request_replies = Rc::clone(&replies);
+
+        conn.start_receive(rule, Box::new(move |call, conn| {
+            if let Some(replies) = tree.handle(&call) {
+                for reply in replies {
+                    conn.send(reply).ok();
+                }
+            }
+
+            if let Some(request) = request.borrow_mut().take() {
+                request.start(call, &mut manager.borrow_mut(), &request_replies);
+            }
+
+            true
+        }));
+
+        Ok(Service { conn, events, replies })
+    }
+
+    /// Answers method calls that have arrived or whose requests have
+    /// finished, and sends signals for events published since this was last
+    /// called, doesn't block
+    pub fn process(&mut self) {
+        while let Ok(true) = self.conn.process(Duration::from_millis(0)) {}
+
+        for reply in self.replies.borrow_mut().drain(..) {
+            self.conn.send(reply).ok();
+        }
+
+        for json in self.events.try_iter() {
+            if let Some(signal) = serde_json::from_str(&json).ok().and_then(signal) {
+                self.conn.send(signal).ok();
+            }
+        }
+    }
+}
+
+/// The signal for an event, if there is one
+fn signal(event: PublishedEvent) -> Option<Message> {
+    let object_path = Path::new(event.object_path?).ok()?;
+    let device = event.device.unwrap_or_default();
+    let message = |member: &'static str| {
+        Message::signal(&Path::from(OBJECT_PATH), &Interface::from(INTERFACE), &Member::from(member))
+    };
+
+    match event.event.as_str() {
+        "attached" => Some(message("DeviceAdded").append2(object_path, device)),
+        "mounted" => Some(message("DeviceMounted").append3(object_path, device, event.mount_path.unwrap_or_default())),
+        "removed" => Some(message("DeviceRemoved").append2(object_path, device)),
+        _ => None
+    }
+}
+
+/// What's known about a device, as `ListDevices` reports it and the CLI
+/// shows it
+#[derive(Debug, Serialize)]
+pub struct DeviceStatus {
+    /// UDisks2 object path of the block device
+    pub object_path: String,
+    /// Device file e.g. `/dev/sdb1`
+    pub device: String,
+    /// UUID of the filesystem or LUKS container
+    pub uuid: Option<String>,
+    /// Filesystem label
+    pub label: Option<String>,
+    /// Type of what's on the device e.g. `vfat` or `crypto_LUKS`
+    pub fstype: Option<String>,
+    /// Size in bytes
+    pub size: u64,
+    /// Whether the device holds a filesystem that can be mounted
+    pub filesystem: bool,
+    /// Where the filesystem is mounted
+    pub mount_path: Option<String>,
+    /// Whether the device is an encrypted container
+    pub encrypted: bool,
+    /// Object path of the unlocked device, if it's unlocked
+    pub cleartext_device: Option<String>,
+    /// Object path of the encrypted device this was unlocked from
+    pub backing_device: Option<String>,
+    /// Object path of the drive the device is on
+    pub drive: Option<String>,
+    /// Vendor and model of the drive
+    pub drive_name: Option<String>,
+    /// Whether the drive can be removed
+    pub removable: Option<bool>
+}
+
+impl DeviceStatus {
+    /// The status of a device on `drive`
+    pub fn new(device: &Block, drive: Option<&Drive>) -> Self {
+        DeviceStatus {
+            object_path: device.object_path.to_string(),
+            device: device.device.to_owned(),
+            uuid: device.uuid.to_owned(),
+            label: device.label.to_owned(),
+            fstype: device.id_type.to_owned(),
+            size: device.size,
+            filesystem: device.fs_info.is_some(),
+            mount_path: manager::mount_path(device).map(str::to_owned),
+            encrypted: device.enc_info.is_some(),
+            cleartext_device: device.enc_info.as_ref().and_then(|enc_info| enc_info.cleartext_device.to_owned()),
+            backing_device: device.crypto_backing_device.to_owned(),
+            drive: drive.map(|drive| drive.object_path.to_string()),
+            drive_name: drive.map(Drive::name),
+            removable: drive.map(|drive| drive.removable)
+        }
+    }
+
+    /// The status as a dictionary, details that aren't known are left out
+    fn to_properties(&self) -> DeviceProperties {
+        let mut properties: DeviceProperties = HashMap::new();
+        let mut insert = |key: &'static str, value: Box<dyn RefArg>| { properties.insert(key, Variant(value)); };
+        let path = |path: &str| Box::new(Path::from(path.to_owned()));
+
+        insert("object_path", path(&self.object_path));
+        insert("device", Box::new(self.device.to_owned()));
+        insert("size", Box::new(self.size));
+        insert("filesystem", Box::new(self.filesystem));
+        insert("encrypted", Box::new(self.encrypted));
+
+        let strings = [("uuid", &self.uuid), ("label", &self.label), ("fstype", &self.fstype), ("mount_path", &self.mount_path), ("drive_name", &self.drive_name)];
+
+        for (key, value) in strings.iter() {
+            if let Some(value) = value {
+                insert(key, Box::new(value.to_owned()));
+            }
+        }
+
+        let paths = [("cleartext_device", &self.cleartext_device), ("backing_device", &self.backing_device), ("drive", &self.drive)];
+
+        for (key, value) in paths.iter() {
+            if let Some(value) = value {
+                insert(key, path(value));
+            }
+        }
+
+        if let Some(removable) = self.removable {
+            insert("removable", Box::new(removable));
+        }
+
+        properties
+    }
+
+    /// Reads a status from a `ListDevices` dictionary
+    pub fn from_properties(properties: &HashMap<String, Variant<Box<dyn RefArg>>>) -> Option<Self> {
+        let string = |key: &str| properties.get(key).and_then(|value| value.0.as_str()).map(str::to_owned);
+        let flag = |key: &str| properties.get(key).and_then(|value| value.0.as_u64()).map(|value| value != 0);
+
+        Some(DeviceStatus {
+            object_path: string("object_path")?,
+            device: string("device")?,
+            uuid: string("uuid"),
+            label: string("label"),
+            fstype: string("fstype"),
+            size: properties.get("size").and_then(|value| value.0.as_u64()).unwrap_or(0),
+            filesystem: flag("filesystem").unwrap_or(false),
+            mount_path: string("mount_path"),
+            encrypted: flag("encrypted").unwrap_or(false),
+            cleartext_device: string("cleartext_device"),
+            backing_device: string("backing_device"),
+            drive: string("drive"),
+            drive_name: string("drive_name"),
+            removable: flag("removable")
+        })
+    }
+}
@@ -1,9 +1,13 @@
 use dbus::strings::Path;
 use dbus::arg::{Variant, RefArg};
-use crate::udisks2::Udisks2InterfacesAndProps;
+use crate::udisks2::{Udisks2InterfacesAndProps, Udisks2Properties};
 use crate::udisks2::Interface;
 use super::{Filesystem, Encrypted};
 
+const BLOCK_INTERFACE: &str = "org.freedesktop.UDisks2.Block";
+const FILESYSTEM_INTERFACE: &str = "org.freedesktop.UDisks2.Filesystem";
+const ENCRYPTED_INTERFACE: &str = "org.freedesktop.UDisks2.Encrypted";
+
 #[derive(Clone, Debug, Default)]
 pub struct Block {
     pub interfaces: Vec<Interface>,
@@ -14,6 +18,9 @@ pub struct Block {
     pub symlinks: Option<Vec<String>>,
     pub device_number: Option<u64>,
     pub label: Option<String>,
+    pub id_type: Option<String>,
+    pub size: u64,
+    pub hint_icon_name: Option<String>,
     pub fs_info: Option<FsInfo>,
     pub enc_info: Option<EncInfo>,
     pub drive: Option<String>,
@@ -43,54 +50,20 @@ pub struct Block {
 
 impl Block {
     pub fn new(object_path: &Path<'static>, interfaces_and_properties: &Udisks2InterfacesAndProps) -> Option<Self> {
-        if let Some(block_interface) = interfaces_and_properties.get("org.freedesktop.UDisks2.Block") {
+        if let Some(block_interface) = interfaces_and_properties.get(BLOCK_INTERFACE) {
             let mut block = Self {
                 object_path: object_path.to_owned(),
                 ..Default::default()
             };
 
-            for (key, value) in block_interface {
-                match key.as_str() {
-                    "IdUUID" => block.uuid = get_string(value),
-                    "IdLabel" => block.label = get_string(value),
-                    "Device" => block.device = get_byte_string(value)?,
-                    "PreferredDevice" => block.preferred_device = get_byte_string(value)?,
-                    "Symlinks" => block.symlinks = get_byte_strings(value),
-                    "DeviceNumber" => block.device_number = get_u64(value),
-                    "Drive" => block.drive = get_string(value),
-                    "CryptoBackingDevice" => block.crypto_backing_device = get_string(value),
-                    _ => ()
-                }
-            }
-
-            if let Some(fs_interface) = interfaces_and_properties.get("org.freedesktop.UDisks2.Filesystem") {
-                let mut fs = FsInfo::default();
+            block.update_block(block_interface)?;
 
-                for (key, value) in fs_interface {
-                    match key.as_str() {
-                        "MountPoints" => fs.mount_paths = get_byte_strings(value),
-                        _ => ()
-                    }
-                }
-
-                block.fs_info = Some(fs);
-                block.interfaces.push(Interface::Filesystem);
+            if let Some(fs_interface) = interfaces_and_properties.get(FILESYSTEM_INTERFACE) {
+                block.update_properties(FILESYSTEM_INTERFACE, fs_interface);
             } 
 
-            if let Some(enc_interface) = interfaces_and_properties.get("org.freedesktop.UDisks2.Encrypted") {
-                let mut enc = EncInfo::default();
-
-                for (key, value) in enc_interface {
-                    match key.as_str() {
-                        "CleartextDevice" => {
-                            enc.cleartext_device = get_string(value).and_then(|s| {if s == "/" { None } else { Some(s) }})
-                        },
-                        _ => ()
-                    }
-                }
-
-                block.enc_info = Some(enc);
-                block.interfaces.push(Interface::Encrypted)
+            if let Some(enc_interface) = interfaces_and_properties.get(ENCRYPTED_INTERFACE) {
+                block.update_properties(ENCRYPTED_INTERFACE, enc_interface);
             } 
 
             Some(block)
@@ -99,10 +72,75 @@ impl Block {
         }
     }
 
+    /// Updates the device with properties that have changed on one of its
+    /// interfaces
+    pub fn update_properties(&mut self, interface: &str, properties: &Udisks2Properties) {
+        match interface {
+            BLOCK_INTERFACE => {
+                self.update_block(properties);
+            },
+            FILESYSTEM_INTERFACE => {
+                let fs = self.fs_info.get_or_insert_with(FsInfo::default);
+
+                if let Some(value) = properties.get("MountPoints") {
+                    fs.mount_paths = get_byte_strings(value);
+                }
+
+                if !self.has_interface(Interface::Filesystem) {
+                    self.interfaces.push(Interface::Filesystem);
+                }
+            },
+            ENCRYPTED_INTERFACE => {
+                let enc = self.enc_info.get_or_insert_with(EncInfo::default);
+
+                if let Some(value) = properties.get("CleartextDevice") {
+                    enc.cleartext_device = get_string(value).and_then(|s| {if s == "/" { None } else { Some(s) }})
+                }
+
+                if !self.has_interface(Interface::Encrypted) {
+                    self.interfaces.push(Interface::Encrypted);
+                }
+            },
+            _ => ()
+        }
+    }
+
+    fn update_block(&mut self, properties: &Udisks2Properties) -> Option<()> {
+        for (key, value) in properties {
+            match key.as_str() {
+                "IdUUID" => self.uuid = get_string(value),
+                "IdLabel" => self.label = get_string(value),
+                "IdType" => self.id_type = get_string(value),
+                "Size" => self.size = get_u64(value).unwrap_or(0),
+                "HintIconName" => self.hint_icon_name = get_string(value).filter(|icon| !icon.is_empty()),
+                "Device" => self.device = get_byte_string(value)?,
+                "PreferredDevice" => self.preferred_device = get_byte_string(value)?,
+                "Symlinks" => self.symlinks = get_byte_strings(value),
+                "DeviceNumber" => self.device_number = get_u64(value),
+                // UDisks2 uses "/" when there's no such object
+                "Drive" => self.drive = get_string(value).filter(|drive| drive != "/"),
+                "CryptoBackingDevice" => self.crypto_backing_device = get_string(value).filter(|backing_device| backing_device != "/"),
+                _ => ()
+            }
+        }
+
+        Some(())
+    }
+
     pub fn has_interface(&self, interface: Interface) -> bool {
         self.interfaces.contains(&interface)
     }
 
+    /// Whether `name` is the device's object path, device file, one of the
+    /// device file's symlinks, UUID or label
+    pub fn matches(&self, name: &str) -> bool {
+        &*self.object_path == name
+            || self.device == name
+            || self.uuid.as_deref() == Some(name)
+            || self.label.as_deref() == Some(name)
+            || self.symlinks.iter().flatten().any(|symlink| symlink == name)
+    }
+
     pub fn as_fs(&self) -> Option<Filesystem> {
         if self.has_interface(Interface::Filesystem) {
             Some(Filesystem { device: self.to_owned() })
@@ -0,0 +1,71 @@
+// This code was autogenerated with dbus-codegen-rust, see https://github.com/diwic/dbus-rs
+
+use dbus as dbus;
+use dbus::arg;
+use dbus::blocking;
+
+pub trait UDisks2Drive {
+    fn eject(&self, options: ::std::collections::HashMap<&str, arg::Variant<Box<dyn arg::RefArg>>>) -> Result<(), dbus::Error>;
+    fn set_configuration(&self, value: ::std::collections::HashMap<&str, arg::Variant<Box<dyn arg::RefArg>>>, options: ::std::collections::HashMap<&str, arg::Variant<Box<dyn arg::RefArg>>>) -> Result<(), dbus::Error>;
+    fn power_off(&self, options: ::std::collections::HashMap<&str, arg::Variant<Box<dyn arg::RefArg>>>) -> Result<(), dbus::Error>;
+    fn get_vendor(&self) -> Result<String, dbus::Error>;
+    fn get_model(&self) -> Result<String, dbus::Error>;
+    fn get_serial(&self) -> Result<String, dbus::Error>;
+    fn get_connection_bus(&self) -> Result<String, dbus::Error>;
+    fn get_removable(&self) -> Result<bool, dbus::Error>;
+    fn get_media_removable(&self) -> Result<bool, dbus::Error>;
+    fn get_optical(&self) -> Result<bool, dbus::Error>;
+    fn get_ejectable(&self) -> Result<bool, dbus::Error>;
+    fn get_can_power_off(&self) -> Result<bool, dbus::Error>;
+}
+
+impl<'a, C: ::std::ops::Deref<Target=blocking::Connection>> UDisks2Drive for blocking::Proxy<'a, C> {
+
+    fn eject(&self, options: ::std::collections::HashMap<&str, arg::Variant<Box<dyn arg::RefArg>>>) -> Result<(), dbus::Error> {
+        self.method_call("org.freedesktop.UDisks2.Drive", "Eject", (options, ))
+    }
+
+    fn set_configuration(&self, value: ::std::collections::HashMap<&str, arg::Variant<Box<dyn arg::RefArg>>>, options: ::std::collections::HashMap<&str, arg::Variant<Box<dyn arg::RefArg>>>) -> Result<(), dbus::Error> {
+        self.method_call("org.freedesktop.UDisks2.Drive", "SetConfiguration", (value, options, ))
+    }
+
+    fn power_off(&self, options: ::std::collections::HashMap<&str, arg::Variant<Box<dyn arg::RefArg>>>) -> Result<(), dbus::Error> {
+        self.method_call("org.freedesktop.UDisks2.Drive", "PowerOff", (options, ))
+    }
+
+    fn get_vendor(&self) -> Result<String, dbus::Error> {
+        <Self as blocking::stdintf::org_freedesktop_dbus::Properties>::get(&self, "org.freedesktop.UDisks2.Drive", "Vendor")
+    }
+
+    fn get_model(&self) -> Result<String, dbus::Error> {
+        <Self as blocking::stdintf::org_freedesktop_dbus::Properties>::get(&self, "org.freedesktop.UDisks2.Drive", "Model")
+    }
+
+    fn get_serial(&self) -> Result<String, dbus::Error> {
+        <Self as blocking::stdintf::org_freedesktop_dbus::Properties>::get(&self, "org.freedesktop.UDisks2.Drive", "Serial")
+    }
+
+    fn get_connection_bus(&self) -> Result<String, dbus::Error> {
+        <Self as blocking::stdintf::org_freedesktop_dbus::Properties>::get(&self, "org.freedesktop.UDisks2.Drive", "ConnectionBus")
+    }
+
+    fn get_removable(&self) -> Result<bool, dbus::Error> {
+        <Self as blocking::stdintf::org_freedesktop_dbus::Properties>::get(&self, "org.freedesktop.UDisks2.Drive", "Removable")
+    }
+
+    fn get_media_removable(&self) -> Result<bool, dbus::Error> {
+        <Self as blocking::stdintf::org_freedesktop_dbus::Properties>::get(&self, "org.freedesktop.UDisks2.Drive", "MediaRemovable")
+    }
+
+    fn get_optical(&self) -> Result<bool, dbus::Error> {
+        <Self as blocking::stdintf::org_freedesktop_dbus::Properties>::get(&self, "org.freedesktop.UDisks2.Drive", "Optical")
+    }
+
+    fn get_ejectable(&self) -> Result<bool, dbus::Error> {
+        <Self as blocking::stdintf::org_freedesktop_dbus::Properties>::get(&self, "org.freedesktop.UDisks2.Drive", "Ejectable")
+    }
+
+    fn get_can_power_off(&self) -> Result<bool, dbus::Error> {
+        <Self as blocking::stdintf::org_freedesktop_dbus::Properties>::get(&self, "org.freedesktop.UDisks2.Drive", "CanPowerOff")
+    }
+}
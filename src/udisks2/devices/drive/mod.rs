@@ -0,0 +1,93 @@
+use dbus::strings::Path;
+use dbus::arg::{Variant, RefArg};
+use dbus::blocking::Connection;
+use std::collections::HashMap;
+use crate::udisks2::Udisks2InterfacesAndProps;
+mod dbus_interface;
+use dbus_interface::UDisks2Drive;
+
+#[derive(Clone, Debug, Default)]
+pub struct Drive {
+    pub object_path: Path<'static>,
+    pub removable: bool,
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub connection_bus: Option<String>,
+    pub media_removable: bool,
+    pub optical: bool,
+    pub ejectable: bool,
+    pub can_power_off: bool
+}
+
+impl Drive {
+    pub fn new(object_path: &Path<'static>, interfaces_and_properties: &Udisks2InterfacesAndProps) -> Option<Self> {
+        if let Some(drive_interface) = interfaces_and_properties.get("org.freedesktop.UDisks2.Drive") {
+            let mut drive = Self {
+                object_path: object_path.to_owned(),
+                ..Default::default()
+            };
+
+            for (key, value) in drive_interface {
+                match key.as_str() {
+                    "Removable" => drive.removable = get_bool(value)?,
+                    "Vendor" => drive.vendor = get_string(value),
+                    "Model" => drive.model = get_string(value),
+                    "Serial" => drive.serial = get_string(value),
+                    "ConnectionBus" => drive.connection_bus = get_string(value),
+                    "MediaRemovable" => drive.media_removable = get_bool(value)?,
+                    "Optical" => drive.optical = get_bool(value)?,
+                    "Ejectable" => drive.ejectable = get_bool(value)?,
+                    "CanPowerOff" => drive.can_power_off = get_bool(value)?,
+                    _ => ()
+                }
+            }
+            
+            Some(drive)
+        } else {
+            None
+        }
+    }
+
+    /// Whether `name` is the drive's object path, name or serial number
+    pub fn matches(&self, name: &str) -> bool {
+        &*self.object_path == name || self.name() == name || self.serial.as_deref() == Some(name)
+    }
+
+    /// A name for the drive that makes sense to the user
+    pub fn name(&self) -> String {
+        match (&self.vendor, &self.model) {
+            (Some(vendor), Some(model)) => format!("{} {}", vendor, model),
+            (Some(name), None) | (None, Some(name)) => name.to_owned(),
+            (None, None) => self.object_path.to_string()
+        }
+    }
+
+    /// Optical drives and drives with removable media have their media
+    /// ejected, other drives are powered off so they can be unplugged.
+    /// Returns false if the drive supports neither
+    pub fn eject(&self) -> Result<bool, dbus::Error> {
+        let conn = Connection::new_system()?;
+        let proxy = conn.with_proxy("org.freedesktop.UDisks2", &self.object_path, std::time::Duration::from_millis(5000));
+
+        if self.ejectable && (self.optical || self.media_removable) {
+            proxy.eject(HashMap::new())?;
+        } else if self.can_power_off {
+            proxy.power_off(HashMap::new())?;
+        } else {
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+}
+
+fn get_bool(arg: &Variant<Box<dyn RefArg>>) -> Option<bool> {
+    arg.0.as_i64().map(|s| s != 0)
+}
+
+fn get_string(arg: &Variant<Box<dyn RefArg>>) -> Option<String> {
+    arg.0.as_str().and_then(|s| {
+        if s.is_empty() { None } else { Some(s.to_owned()) }
+    })
+}
@@ -4,16 +4,25 @@ use dbus::arg::{Variant, RefArg};
 use dbus::strings::Path;
 use super::block;
 use crate::err::MounterError;
+use crate::keyring::Keyring;
 mod dbus_interface;
 use dbus_interface::UDisks2Encrypted;
 
+#[derive(Clone, Debug)]
 pub struct Encrypted {
     pub device: block::Block
 }
 
 impl Encrypted {
-    pub fn unlock(&self, keyfile: Option<String>, password: Option<String>) -> Result<String, MounterError> {
-        let conn = Connection::new_system().expect("Could not connect to system bus");
+    /// Unlocks the device with `keyfile` or else `password`. If neither is
+    /// given the passphrase saved in `keyring` for the device's UUID is
+    /// tried, `NoKeyProvided` means there wasn't one
+    pub fn unlock(&self, keyfile: Option<String>, password: Option<String>, keyring: Option<&Keyring>) -> Result<String, MounterError> {
+        let password = match (&keyfile, password) {
+            (None, None) => keyring.and_then(|keyring| self.saved_password(keyring)),
+            (_, password) => password
+        };
+        let conn = Connection::new_system().map_err(MounterError::UnlockFailed)?;
         let proxy = conn.with_proxy("org.freedesktop.UDisks2", &self.device.object_path, std::time::Duration::from_millis(5000));
 
         if let Some(keyfile_path) = keyfile {
@@ -54,5 +63,24 @@ impl Encrypted {
         }
     }
 
+    /// The passphrase saved in the keyring, a keyring that can't be reached
+    /// is treated like one without a passphrase so the user is asked instead
+    fn saved_password(&self, keyring: &Keyring) -> Option<String> {
+        let uuid = self.device.uuid.as_ref()?;
+
+        keyring.lookup(uuid).unwrap_or_else(|e| {
+            eprintln!("Couldn't look up the passphrase of {} in the keyring: {}", self.device.device, e.message().unwrap_or("D-Bus error"));
+            None
+        })
+    }
+
+    /// Locks the device, removing the cleartext device
+    pub fn lock(&self) -> Result<(), dbus::Error> {
+        let conn = Connection::new_system()?;
+        let proxy = conn.with_proxy("org.freedesktop.UDisks2", &self.device.object_path, std::time::Duration::from_millis(5000));
+
+        proxy.lock(HashMap::new())
+    }
+
 }
 
@@ -5,12 +5,15 @@ mod dbus_interface;
 use super::block;
 use dbus_interface::UDisks2Filesystem;
 
+#[derive(Clone, Debug)]
 pub struct Filesystem {
     pub device: block::Block,
 }
 
 impl Filesystem {
-    pub fn mount(&self) -> Result<String, dbus::Error> {
+    /// Mounts the filesystem, `options` are comma separated mount options and
+    /// `fstype` overrides the detected filesystem type
+    pub fn mount(&self, options: Option<&str>, fstype: Option<&str>) -> Result<String, dbus::Error> {
         if let Some(info) = &self.device.fs_info {
             if let Some(mount_paths) = &info.mount_paths {
                 return Ok(mount_paths[0].to_owned())
@@ -18,8 +21,30 @@ impl Filesystem {
         }
         let conn = Connection::new_system()?;
         let proxy = conn.with_proxy("org.freedesktop.UDisks2", &self.device.object_path, std::time::Duration::from_millis(5000));
-        let options: HashMap<&str, Variant<std::boxed::Box<(dyn RefArg + 'static)>>> = HashMap::new();
+        let mut mount_options: HashMap<&str, Variant<Box<dyn RefArg>>> = HashMap::new();
 
-        proxy.mount(options)
+        if let Some(options) = options {
+            mount_options.insert("options", Variant(Box::new(options.to_owned())));
+        }
+
+        if let Some(fstype) = fstype {
+            mount_options.insert("fstype", Variant(Box::new(fstype.to_owned())));
+        }
+
+        proxy.mount(mount_options)
+    }
+
+    /// Unmounts the filesystem from every mount point. A lazy unmount
+    /// detaches the filesystem straight away even if it's still busy
+    pub fn unmount(&self, lazy: bool) -> Result<(), dbus::Error> {
+        let conn = Connection::new_system()?;
+        let proxy = conn.with_proxy("org.freedesktop.UDisks2", &self.device.object_path, std::time::Duration::from_millis(5000));
+        let mut options: HashMap<&str, Variant<Box<dyn RefArg>>> = HashMap::new();
+
+        if lazy {
+            options.insert("force", Variant(Box::new(true)));
+        }
+
+        proxy.unmount(options)
     }
 }
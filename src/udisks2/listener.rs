@@ -1,13 +1,22 @@
 use dbus::blocking::Connection;
+use dbus::channel::MatchingReceiver;
+use dbus::message::{MatchRule, SignalArgs};
+use dbus::Message;
 use std::time::Duration;
 use std::rc::Rc;
 use super::dbus_interface;
 use crate::udisks2::devices::{Drive, Block};
+use crate::udisks2::Udisks2Properties;
+
+type Handler<F> = Rc<Option<Box<F>>>;
 
 pub struct Listener {
-    drive_added: Rc<Option<Box<dyn Fn(Drive)>>>,
-    block_device_added: Rc<Option<Box<dyn Fn(Block)>>>,
-    object_removed: Rc<Option<Box<dyn Fn(String)>>>
+    drive_added: Handler<dyn Fn(Drive)>,
+    block_device_added: Handler<dyn Fn(Block)>,
+    object_removed: Handler<dyn Fn(String)>,
+    properties_changed: Handler<dyn Fn(String, String, Udisks2Properties)>,
+    tick: Option<Box<dyn Fn()>>,
+    until: Option<Box<dyn Fn() -> bool>>
 }
 
 impl Listener {
@@ -15,34 +24,63 @@ impl Listener {
         Listener {
             drive_added: Rc::new(None),
             block_device_added: Rc::new(None),
-            object_removed: Rc::new(None)
+            object_removed: Rc::new(None),
+            properties_changed: Rc::new(None),
+            tick: None,
+            until: None
         }
     }
 
-    pub fn drive_added<F: 'static>(&mut self, callback: F)
-        where F: Fn(Drive)
+    pub fn drive_added<F>(&mut self, callback: F)
+        where F: Fn(Drive) + 'static
     {
         self.drive_added = Rc::new(Some(Box::new(callback)));
     }
 
-    pub fn block_device_added<F: 'static>(&mut self, callback: F)
-        where F: Fn(Block)
+    pub fn block_device_added<F>(&mut self, callback: F)
+        where F: Fn(Block) + 'static
     {
         self.block_device_added = Rc::new(Some(Box::new(callback)));
     }
 
-    pub fn object_removed<F: 'static>(&mut self, callback: F)
-        where F: Fn(String)
+    pub fn object_removed<F>(&mut self, callback: F)
+        where F: Fn(String) + 'static
     {
         self.object_removed = Rc::new(Some(Box::new(callback)));
     }
 
+    /// Called with the object path, interface name and changed properties
+    /// whenever a property of a UDisks2 object changes, e.g. when a
+    /// filesystem is mounted or unmounted by another program
+    pub fn properties_changed<F>(&mut self, callback: F)
+        where F: Fn(String, String, Udisks2Properties) + 'static
+    {
+        self.properties_changed = Rc::new(Some(Box::new(callback)));
+    }
+
+    /// Called periodically from the event loop, whether or not any signals
+    /// were received
+    pub fn tick<F>(&mut self, callback: F)
+        where F: Fn() + 'static
+    {
+        self.tick = Some(Box::new(callback));
+    }
+
+    /// Makes `run` return once `done` gives true, it's checked after
+    /// signals are handled and after each tick
+    pub fn until<F>(&mut self, done: F)
+        where F: Fn() -> bool + 'static
+    {
+        self.until = Some(Box::new(done));
+    }
+
     pub fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
         let mut conn = Connection::new_system().expect("Could not connect to system bus");
         let proxy = conn.with_proxy("org.freedesktop.UDisks2", "/org/freedesktop/UDisks2", Duration::from_millis(5000));
         let drive_added = Rc::clone(&self.drive_added);
         let block_device_added = Rc::clone(&self.block_device_added);
         let object_removed = Rc::clone(&self.object_removed);
+        let properties_changed = Rc::clone(&self.properties_changed);
 
         proxy.match_signal(move |signal: dbus_interface::OrgFreedesktopDBusObjectManagerInterfacesAdded, _conn: &Connection| {
             if let Some(drive) = Drive::new(&signal.object_path, &signal.interfaces_and_properties) {
@@ -61,13 +99,62 @@ impl Listener {
         }).expect("Could not listen for Interfaces Added signal");
 
         proxy.match_signal(move |signal: dbus_interface::OrgFreedesktopDBusObjectManagerInterfacesRemoved, _conn: &Connection| {
-            if let Some(removed_object_handler) = &*object_removed {
-                removed_object_handler(signal.object_path.to_string());
+            // Objects lose interfaces without being removed, e.g. when a
+            // device is reformatted, so only report whole objects going away
+            let removed = signal.interfaces.iter().any(|interface| {
+                interface == "org.freedesktop.UDisks2.Block" || interface == "org.freedesktop.UDisks2.Drive"
+            });
+
+            if removed {
+                if let Some(removed_object_handler) = &*object_removed {
+                    removed_object_handler(signal.object_path.to_string());
+                }
             }
 
             true
         }).expect("Could not listen for Interfaces Removed signal");
 
-        loop { conn.process(Duration::from_millis(1000))?; }
+        // PropertiesChanged is sent from each object's own path, the match
+        // rule has to cover every path under UDisks2 which the MatchRule type
+        // can't express so the match is added by hand
+        let properties_rule = MatchRule::new_signal(
+            dbus_interface::OrgFreedesktopDBusPropertiesPropertiesChanged::INTERFACE,
+            dbus_interface::OrgFreedesktopDBusPropertiesPropertiesChanged::NAME
+        ).static_clone();
+
+        conn.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", Duration::from_millis(5000))
+            .method_call::<(), _, _, _>(
+                "org.freedesktop.DBus",
+                "AddMatch",
+                (format!("{},sender='org.freedesktop.UDisks2',path_namespace='/org/freedesktop/UDisks2'", properties_rule.match_str()), )
+            )
+            .expect("Could not listen for Properties Changed signal");
+
+        conn.start_receive(properties_rule, Box::new(move |message: Message, _conn: &Connection| {
+            let object_path = message.path().map(|path| path.to_string());
+            let signal: Option<dbus_interface::OrgFreedesktopDBusPropertiesPropertiesChanged> = message.read_all().ok();
+
+            if let (Some(object_path), Some(signal)) = (object_path, signal) {
+                if let Some(properties_changed_handler) = &*properties_changed {
+                    properties_changed_handler(object_path, signal.interface_name, signal.changed_properties);
+                }
+            }
+
+            true
+        }));
+
+        loop {
+            conn.process(Duration::from_millis(500))?;
+
+            if let Some(tick_handler) = &self.tick {
+                tick_handler();
+            }
+
+            if let Some(done) = &self.until {
+                if done() {
+                    return Ok(());
+                }
+            }
+        }
     }
 }
@@ -9,6 +9,7 @@ pub mod devices;
 mod listener;
 pub use listener::Listener;
 
+pub type Udisks2Properties = HashMap<String, Variant<std::boxed::Box<dyn RefArg + 'static>>>;
 pub type Udisks2InterfacesAndProps = HashMap<String, HashMap<String, Variant<std::boxed::Box<(dyn RefArg + 'static)>>>>;
 pub type Udisks2ManagedObjects = HashMap<Path<'static>, Udisks2InterfacesAndProps>;
 
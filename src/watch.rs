@@ -0,0 +1,262 @@
+use glob::Pattern;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+use crate::config::EventSettings;
+use crate::events::{self, Event};
+use crate::manager;
+use crate::notices::Details;
+use crate::udisks2::{self, Udisks2Properties, devices::{Block, Drive}};
+
+/// Events only the daemon knows about, these are passed on from its event
+/// socket while everything else is seen straight from UDisks2
+const DAEMON_EVENTS: &[&str] = &["policy", "mount_vetoed"];
+
+/// Which events `watch` prints, an event has to match everything given
+#[derive(Debug, Default)]
+pub struct WatchFilter {
+    /// Glob pattern for the UUID of the device
+    pub uuid: Option<String>,
+    /// Glob pattern for the label of the device
+    pub label: Option<String>,
+    /// Names of the events to print, every event if it's empty
+    pub events: Vec<String>,
+    /// Stop after the first event that matches
+    pub once: bool
+}
+
+/// A filter with its patterns parsed
+struct Filter {
+    uuid: Option<Pattern>,
+    label: Option<Pattern>,
+    events: Vec<String>,
+    once: bool
+}
+
+impl Filter {
+    fn matches(&self, event: &str, uuid: Option<&str>, label: Option<&str>) -> bool {
+        let matches = |pattern: &Option<Pattern>, value: Option<&str>| match (pattern, value) {
+            (Some(pattern), Some(value)) => pattern.matches(value),
+            (Some(_), None) => false,
+            (None, _) => true
+        };
+
+        (self.events.is_empty() || self.events.iter().any(|name| name == event))
+            && matches(&self.uuid, uuid)
+            && matches(&self.label, label)
+    }
+}
+
+/// Prints events as lines of JSON as they happen. Drives and devices coming
+/// and going, mounts and unlocks are seen from UDisks2 so they're printed
+/// whether or not udman is running, the decisions udman makes about them
+/// come from its event socket
+pub fn watch(filter: WatchFilter, settings: &EventSettings) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = Filter {
+        uuid: filter.uuid.as_deref().map(Pattern::new).transpose()?,
+        label: filter.label.as_deref().map(Pattern::new).transpose()?,
+        events: filter.events,
+        once: filter.once
+    };
+
+    let watcher = Rc::new(RefCell::new(Watcher::new(filter, udisks2::current_state()?)));
+    let mut listener = udisks2::Listener::new();
+
+    let watcher_clone = watcher.clone();
+    listener.drive_added(move |drive: Drive| watcher_clone.borrow_mut().drive_added(drive));
+
+    let watcher_clone = watcher.clone();
+    listener.block_device_added(move |device: Block| watcher_clone.borrow_mut().device_added(device));
+
+    let watcher_clone = watcher.clone();
+    listener.object_removed(move |object_path: String| watcher_clone.borrow_mut().object_removed(&object_path));
+
+    let watcher_clone = watcher.clone();
+    listener.properties_changed(move |object_path: String, interface: String, properties| {
+        watcher_clone.borrow_mut().properties_changed(&object_path, &interface, &properties);
+    });
+
+    let daemon = events::socket_path(settings).map(daemon_events);
+    let watcher_clone = watcher.clone();
+    listener.tick(move || {
+        for json in daemon.iter().flat_map(Receiver::try_iter) {
+            watcher_clone.borrow_mut().daemon_event(&json);
+        }
+    });
+
+    let watcher_clone = watcher.clone();
+    listener.until(move || watcher_clone.borrow().done);
+    listener.run()?;
+
+    let error = watcher.borrow_mut().error.take();
+
+    match error {
+        // Whatever was reading the events has gone away
+        Some(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        Some(e) => Err(e.into()),
+        None => Ok(())
+    }
+}
+
+/// Keeps track of drives and devices so changes to them can be reported
+/// with everything that's known about them
+struct Watcher {
+    filter: Filter,
+    drives: HashMap<String, Drive>,
+    devices: HashMap<String, Block>,
+    done: bool,
+    error: Option<io::Error>
+}
+
+impl Watcher {
+    fn new(filter: Filter, state: udisks2::Udisks2ManagedObjects) -> Self {
+        let mut watcher = Watcher { filter, drives: HashMap::new(), devices: HashMap::new(), done: false, error: None };
+
+        for (object_path, interfaces_and_properties) in state.iter() {
+            if let Some(drive) = Drive::new(object_path, interfaces_and_properties) {
+                watcher.drives.insert(object_path.to_string(), drive);
+            }
+
+            if let Some(device) = Block::new(object_path, interfaces_and_properties) {
+                watcher.devices.insert(object_path.to_string(), device);
+            }
+        }
+
+        watcher
+    }
+
+    fn drive_added(&mut self, drive: Drive) {
+        self.drive_event("drive_attached", &drive);
+        self.drives.insert(drive.object_path.to_string(), drive);
+    }
+
+    fn device_added(&mut self, device: Block) {
+        self.device_event("attached", &device, None);
+        self.devices.insert(device.object_path.to_string(), device);
+    }
+
+    fn object_removed(&mut self, object_path: &str) {
+        if let Some(device) = self.devices.get(object_path).cloned() {
+            self.device_event("removed", &device, None);
+            self.devices.remove(object_path);
+        }
+
+        if let Some(drive) = self.drives.remove(object_path) {
+            self.drive_event("drive_removed", &drive);
+        }
+    }
+
+    fn properties_changed(&mut self, object_path: &str, interface: &str, properties: &Udisks2Properties) {
+        let mut device = match self.devices.get(object_path).cloned() {
+            Some(device) => device,
+            None => return
+        };
+
+        let old_mount_path = manager::mount_path(&device).map(str::to_owned);
+        let was_unlocked = cleartext_device(&device).is_some();
+
+        device.update_properties(interface, properties);
+
+        match (&old_mount_path, manager::mount_path(&device)) {
+            (None, Some(_)) => self.device_event("mounted", &device, None),
+            (Some(old_mount_path), None) => self.device_event("unmounted", &device, Some(old_mount_path)),
+            _ => ()
+        }
+
+        match (was_unlocked, cleartext_device(&device).is_some()) {
+            (false, true) => self.device_event("unlocked", &device, None),
+            (true, false) => self.device_event("locked", &device, None),
+            _ => ()
+        }
+
+        self.devices.insert(object_path.to_owned(), device);
+    }
+
+    /// Passes on an event from the daemon's socket if it's one UDisks2
+    /// doesn't tell us about
+    fn daemon_event(&mut self, json: &str) {
+        let value: serde_json::Value = match serde_json::from_str(json) {
+            Ok(value) => value,
+            Err(_) => return
+        };
+
+        let field = |key: &str| value.get(key).and_then(serde_json::Value::as_str);
+        let event = field("event").unwrap_or_default();
+
+        if DAEMON_EVENTS.contains(&event) && self.filter.matches(event, field("uuid"), field("label")) {
+            self.print(json);
+        }
+    }
+
+    fn drive_event(&mut self, event: &'static str, drive: &Drive) {
+        let name = drive.name();
+        let details = Details { drive: Some(&name), ..Details::default() };
+
+        self.event(&Event { object_path: Some(&drive.object_path), ..Event::new(event, None, &details) });
+    }
+
+    /// Reports an event for a device, `mount_path` is given for devices that
+    /// are no longer mounted there
+    fn device_event(&mut self, event: &'static str, device: &Block, mount_path: Option<&str>) {
+        let drive = device.drive.as_ref().and_then(|drive| self.drives.get(drive)).map(Drive::name);
+        let details = manager::details(device);
+        let details = Details { drive: drive.as_deref(), mount_path: mount_path.or(details.mount_path), ..details };
+
+        self.event(&Event::new(event, Some(device), &details));
+    }
+
+    fn event(&mut self, event: &Event) {
+        if self.filter.matches(event.event, event.uuid, event.label) {
+            if let Ok(json) = serde_json::to_string(event) {
+                self.print(&json);
+            }
+        }
+    }
+
+    fn print(&mut self, json: &str) {
+        if self.done {
+            return;
+        }
+
+        let mut stdout = io::stdout();
+
+        match writeln!(stdout, "{}", json).and_then(|_| stdout.flush()) {
+            Ok(()) => self.done = self.filter.once,
+            Err(e) => {
+                self.error = Some(e);
+                self.done = true;
+            }
+        }
+    }
+}
+
+fn cleartext_device(device: &Block) -> Option<&String> {
+    device.enc_info.as_ref()?.cleartext_device.as_ref()
+}
+
+/// Reads events from udman's event socket on a thread, connecting again if
+/// udman isn't running yet or is restarted
+fn daemon_events(path: PathBuf) -> Receiver<String> {
+    let (sender, events) = mpsc::channel();
+
+    thread::spawn(move || loop {
+        if let Ok(stream) = UnixStream::connect(&path) {
+            for line in BufReader::new(stream).lines().map_while(Result::ok) {
+                // Nothing is watching any more
+                if sender.send(line).is_err() {
+                    return;
+                }
+            }
+        }
+
+        thread::sleep(Duration::from_secs(1));
+    });
+
+    events
+}
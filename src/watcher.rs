@@ -0,0 +1,79 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use inotify::{Inotify, WatchMask};
+
+/// Watches the configuration file for changes and listens for SIGHUP so the
+/// configuration can be reloaded without restarting
+pub struct ConfigWatcher {
+    path: PathBuf,
+    inotify: Option<Inotify>,
+    hangup: Arc<AtomicBool>,
+    buffer: [u8; 4096]
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> Self {
+        let hangup = Arc::new(AtomicBool::new(false));
+
+        if let Err(e) = signal_hook::flag::register(signal_hook::SIGHUP, Arc::clone(&hangup)) {
+            eprintln!("Could not listen for SIGHUP: {}", e);
+        }
+
+        ConfigWatcher {
+            path: path.to_owned(),
+            inotify: watch_directory(path),
+            hangup,
+            buffer: [0; 4096]
+        }
+    }
+
+    /// Returns true if a SIGHUP has been received since the last time this
+    /// was called
+    pub fn hangup(&self) -> bool {
+        self.hangup.swap(false, Ordering::Relaxed)
+    }
+
+    /// Returns true if the config file has changed since the last time this
+    /// was called
+    pub fn file_changed(&mut self) -> bool {
+        let mut changed = false;
+        let file_name = self.path.file_name();
+
+        if let Some(inotify) = &mut self.inotify {
+            // Editors often save by writing a new file and renaming it over
+            // the old one, so the directory is watched rather than the file
+            // and events are filtered by name
+            if let Ok(events) = inotify.read_events(&mut self.buffer) {
+                for event in events {
+                    if event.name.is_some() && event.name == file_name {
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        changed
+    }
+}
+
+fn watch_directory(path: &Path) -> Option<Inotify> {
+    let directory = path.parent()?;
+    let mut inotify = Inotify::init().ok()?;
+
+    // A directory that doesn't exist can't be watched, creating it means a
+    // config file written there later is still picked up
+    if let Err(e) = fs::create_dir_all(directory) {
+        eprintln!("Could not create config directory {:?}: {}", directory, e);
+        return None;
+    }
+
+    match inotify.add_watch(directory, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO) {
+        Ok(_) => Some(inotify),
+        Err(e) => {
+            eprintln!("Could not watch config directory {:?}: {}", directory, e);
+            None
+        }
+    }
+}